@@ -1,15 +1,21 @@
 use std::{
+  collections::HashMap,
   env::var_os,
   fs::{self, File, read_dir},
   path::PathBuf,
   slice,
 };
 
-use anyhow::{Context, Error as AnyError};
+use anyhow::{Context, Error as AnyError, anyhow};
 use regex::Regex;
 use zbus_xml::Node;
 use zbus_xmlgen::write_interfaces;
 
+use crate::modules::register_module;
+
+#[path = "build/modules.rs"]
+mod modules;
+
 fn proxy_defaults(interface: &str) -> Option<(&'static str, Option<&'static str>)> {
   match interface {
     "org.gnome.Mutter.DisplayConfig" => Some((
@@ -51,8 +57,11 @@ fn main() -> Result<(), AnyError> {
   xml_dir.push("dbus");
 
   let mut interfaces_impl = Vec::new();
+  let mut mod_sources: HashMap<String, PathBuf> = HashMap::new();
 
-  for entry in read_dir(xml_dir)? {
+  // new files in the directory have to trigger a rebuild as well
+  println!("cargo:rerun-if-changed={}", xml_dir.display());
+  for entry in read_dir(&xml_dir).with_context(|| format!("failed to read {}", xml_dir.display()))? {
     let path = match entry {
       Ok(e) => e.path(),
       Err(_) => continue,
@@ -60,14 +69,17 @@ fn main() -> Result<(), AnyError> {
     if !path.is_file() {
       continue;
     }
-    let (fdo_standard_ifaces, needed_ifaces): (Vec<_>, Vec<_>) = Node::from_reader(File::open(path.to_path_buf())?)?
+    println!("cargo:rerun-if-changed={}", path.display());
+    let file = File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+    let (fdo_standard_ifaces, needed_ifaces): (Vec<_>, Vec<_>) = Node::from_reader(file)
+      .with_context(|| format!("failed to parse {}", path.display()))?
       .interfaces()
       .iter()
       .cloned()
       .partition(|i| i.name().starts_with("org.freedesktop.DBus"));
 
     for iface in needed_ifaces {
-      let mod_name = iface.name().as_str().to_lowercase().replace(".", "_");
+      let mod_name = register_module(&mut mod_sources, iface.name().as_str(), &path)?;
       let iface_impl = apply_proxy_defaults(
         iface.name().as_str(),
         write_interfaces(
@@ -79,7 +91,7 @@ fn main() -> Result<(), AnyError> {
           "build.rs",
           "build.rs",
         )
-        .unwrap(),
+        .map_err(|e| anyhow!("failed to generate {} from {}: {}", iface.name(), path.display(), e))?,
       );
       interfaces_impl.push(format!("pub mod {} {{ {} }}", mod_name, iface_impl));
     }
@@ -0,0 +1,78 @@
+//! Helpers for build.rs that are worth testing. Cargo never builds build scripts in test mode, so the crate includes
+//! this file in its own tests as well.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Error as AnyError, bail};
+
+/// Names the module generated for `interface` from `path`, failing if another file already generated it.
+pub fn register_module(
+  mod_sources: &mut HashMap<String, PathBuf>,
+  interface: &str,
+  path: &Path,
+) -> Result<String, AnyError> {
+  let mod_name = interface.to_lowercase().replace(".", "_");
+  if let Some(other) = mod_sources.insert(mod_name.clone(), path.to_path_buf()) {
+    bail!(
+      "module {} for interface {} is generated from both {} and {}",
+      mod_name,
+      interface,
+      other.display(),
+      path.display()
+    );
+  }
+
+  Ok(mod_name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn modules_are_named_after_interfaces() {
+    let mut mod_sources = HashMap::new();
+
+    assert_eq!(
+      register_module(
+        &mut mod_sources,
+        "org.gnome.Mutter.ScreenCast",
+        Path::new("org.gnome.Mutter.ScreenCast.xml")
+      )
+      .unwrap(),
+      "org_gnome_mutter_screencast"
+    );
+    assert_eq!(
+      register_module(
+        &mut mod_sources,
+        "org.gnome.Mutter.ScreenCast.Session",
+        Path::new("org.gnome.Mutter.ScreenCast.xml")
+      )
+      .unwrap(),
+      "org_gnome_mutter_screencast_session"
+    );
+  }
+
+  #[test]
+  fn interface_defined_twice_names_both_files() {
+    let mut mod_sources = HashMap::new();
+    register_module(&mut mod_sources, "org.gnome.Shell.Introspect", Path::new("a")).unwrap();
+
+    let err = register_module(&mut mod_sources, "org.gnome.Shell.Introspect", Path::new("b")).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "module org_gnome_shell_introspect for interface org.gnome.Shell.Introspect is generated from both a and b"
+    );
+  }
+
+  #[test]
+  fn interfaces_differing_only_in_case_collide() {
+    let mut mod_sources = HashMap::new();
+    register_module(&mut mod_sources, "org.gnome.Shell.Introspect", Path::new("a")).unwrap();
+
+    assert!(register_module(&mut mod_sources, "org.gnome.shell.introspect", Path::new("b.xml")).is_err());
+  }
+}
@@ -17,6 +17,7 @@ in {
       ./resources
       ./src
       ./build.rs
+      ./build
       ./Cargo.toml
       ./Cargo.lock
       ./Makefile
@@ -0,0 +1,158 @@
+use zbus::{Connection, fdo::DBusProxy, names::BusName};
+
+use crate::backend::generated::org_gnome_mutter_screencast::ScreenCastProxy;
+
+const MUTTER_SCREENCAST_NAME: &str = "org.gnome.Mutter.ScreenCast";
+const MUTTER_DISPLAY_CONFIG_NAME: &str = "org.gnome.Mutter.DisplayConfig";
+const SHELL_INTROSPECT_NAME: &str = "org.gnome.Shell.Introspect";
+
+/// First ScreenCast API version that takes the `cursor-mode` record property.
+pub const CURSOR_MODE_VERSION: i32 = 2;
+/// First ScreenCast API version that takes the `disable-animations` session property.
+pub const DISABLE_ANIMATIONS_VERSION: i32 = 3;
+/// First ScreenCast API version with `RecordWindow` taking a `window-id`.
+pub const RECORD_WINDOW_VERSION: i32 = 2;
+/// First ScreenCast API version with `RecordVirtual`.
+pub const RECORD_VIRTUAL_VERSION: i32 = 3;
+
+/// Compositor services available on the session bus, probed once at startup.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+  /// API version of `org.gnome.Mutter.ScreenCast`, `None` when the service is not running.
+  pub screencast_version: Option<i32>,
+  pub display_config: bool,
+  pub introspect: bool,
+}
+
+impl Capabilities {
+  pub async fn probe(conn: &Connection) -> Self {
+    let dbus = match DBusProxy::new(conn).await {
+      Ok(p) => p,
+      Err(e) => {
+        tracing::warn!("failed to create DBus proxy, assuming no compositor services: {}", e);
+        return Default::default();
+      }
+    };
+
+    let screencast_version = if has_owner(&dbus, MUTTER_SCREENCAST_NAME).await {
+      match ScreenCastProxy::new(conn).await {
+        Ok(p) => p
+          .version()
+          .await
+          .inspect_err(|e| tracing::warn!("failed to read Mutter ScreenCast version: {}", e))
+          .ok(),
+        Err(e) => {
+          tracing::warn!("failed to create Mutter ScreenCast proxy: {}", e);
+          None
+        }
+      }
+    } else {
+      None
+    };
+
+    let capabilities = Self {
+      screencast_version,
+      display_config: has_owner(&dbus, MUTTER_DISPLAY_CONFIG_NAME).await,
+      introspect: has_owner(&dbus, SHELL_INTROSPECT_NAME).await,
+    };
+    tracing::info!(?capabilities, "probed compositor capabilities");
+
+    capabilities
+  }
+
+  fn screencast_since(&self, version: i32) -> bool {
+    self.screencast_version.is_some_and(|v| v >= version)
+  }
+
+  pub fn monitor_recording(&self) -> bool {
+    self.display_config && self.screencast_version.is_some()
+  }
+
+  pub fn window_recording(&self) -> bool {
+    self.introspect && self.screencast_since(RECORD_WINDOW_VERSION)
+  }
+
+  pub fn virtual_recording(&self) -> bool {
+    self.screencast_since(RECORD_VIRTUAL_VERSION)
+  }
+
+  pub fn disable_animations(&self) -> bool {
+    self.screencast_since(DISABLE_ANIMATIONS_VERSION)
+  }
+
+  pub fn cursor_modes(&self) -> bool {
+    self.screencast_since(CURSOR_MODE_VERSION)
+  }
+}
+
+async fn has_owner(dbus: &DBusProxy<'_>, name: &'static str) -> bool {
+  let Ok(bus_name) = BusName::try_from(name) else {
+    return false;
+  };
+
+  match dbus.name_has_owner(bus_name).await {
+    Ok(b) => b,
+    Err(e) => {
+      tracing::warn!("failed to check owner of {}: {}", name, e);
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn features_are_gated_on_screencast_version() {
+    // version, window recording, virtual recording, disable animations, cursor modes
+    let cases = [
+      (None, false, false, false, false),
+      (Some(1), false, false, false, false),
+      (Some(2), true, false, false, true),
+      (Some(3), true, true, true, true),
+      (Some(4), true, true, true, true),
+    ];
+
+    for (version, window, virtual_monitor, disable_animations, cursor_modes) in cases {
+      let capabilities = Capabilities {
+        screencast_version: version,
+        display_config: true,
+        introspect: true,
+      };
+      assert_eq!(
+        capabilities.monitor_recording(),
+        version.is_some(),
+        "version {:?}",
+        version
+      );
+      assert_eq!(capabilities.window_recording(), window, "version {:?}", version);
+      assert_eq!(
+        capabilities.virtual_recording(),
+        virtual_monitor,
+        "version {:?}",
+        version
+      );
+      assert_eq!(
+        capabilities.disable_animations(),
+        disable_animations,
+        "version {:?}",
+        version
+      );
+      assert_eq!(capabilities.cursor_modes(), cursor_modes, "version {:?}", version);
+    }
+  }
+
+  #[test]
+  fn missing_services_disable_their_source_type() {
+    let capabilities = Capabilities {
+      screencast_version: Some(4),
+      display_config: false,
+      introspect: false,
+    };
+
+    assert!(!capabilities.monitor_recording());
+    assert!(!capabilities.window_recording());
+    assert!(capabilities.virtual_recording());
+  }
+}
@@ -0,0 +1,68 @@
+use anyhow::Error as AnyError;
+use ashpd::desktop::screencast::{CursorMode, SourceType};
+use futures_util::future::BoxFuture;
+
+/// What a stream was recorded from, written into restore data so the same source can be found again.
+pub enum StreamRestoreData {
+  Monitor { match_string: String, connector: String },
+  Window { app_id: String, title: String },
+  Virtual,
+}
+
+/// A stream recorded by the compositor.
+pub struct CompositorStream {
+  pub id: u32,
+  /// Set once the session is started, `None` if the compositor never provided a node.
+  pub pipewire_node_id: Option<u32>,
+  pub source_type: SourceType,
+  pub position: Option<(i32, i32)>,
+  pub size: Option<(i32, i32)>,
+  pub mapping_id: Option<String>,
+  pub restore_data: StreamRestoreData,
+}
+
+/// Compositor side of a screencast session.
+#[async_trait::async_trait]
+pub trait CompositorSession: Send + Sync {
+  async fn record_monitor(
+    &mut self,
+    id: u32,
+    connector: String,
+    match_string: String,
+    cursor_mode: CursorMode,
+  ) -> Result<(), AnyError>;
+
+  async fn record_window(
+    &mut self,
+    id: u32,
+    window_id: u64,
+    app_id: String,
+    title: String,
+    cursor_mode: CursorMode,
+  ) -> Result<(), AnyError>;
+
+  async fn record_virtual(&mut self, id: u32, cursor_mode: CursorMode) -> Result<(), AnyError>;
+
+  /// Starts every recorded stream and waits for their PipeWire nodes.
+  async fn start(&mut self) -> Result<(), AnyError>;
+
+  async fn stop(&mut self) -> Result<(), AnyError>;
+
+  /// The recorded streams, ordered by id once the session is started.
+  fn streams(&self) -> &[CompositorStream];
+
+  /// A future that resolves once the compositor ends the session on its own, e.g. because the recorded window was
+  /// closed. Can only be taken once.
+  fn take_closed(&mut self) -> Option<BoxFuture<'static, ()>>;
+}
+
+/// Options chosen for a single session.
+pub struct SessionOptions {
+  pub disable_animations: bool,
+}
+
+/// A compositor that can create screencast sessions.
+#[async_trait::async_trait]
+pub trait Compositor: Send + Sync {
+  async fn create_session(&self, options: &SessionOptions) -> Result<Box<dyn CompositorSession>, AnyError>;
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ashpd::desktop::HandleToken;
+use async_channel::Sender;
+use async_lock::Mutex;
+use zbus::{fdo, interface, zvariant::OwnedValue};
+
+use crate::{
+  backend::{
+    ScreencastSession, ScreencastStream, close_session,
+    display_tracker::DisplayStateTracker,
+    find_session_token, resolve_stored_streams,
+    restore::{RestoreData, TitleNormalizer},
+    window_tracker::WindowStateTracker,
+  },
+  common::ToUiMessage,
+  config::Config,
+};
+
+/// Monitor as dumped by `DumpState`: match string, connector, x, y, width, height, scale.
+type MonitorDump = (String, String, i32, i32, i32, i32, f64);
+/// Window as dumped by `DumpState`: id, app id, title.
+type WindowDump = (u64, String, String);
+/// Stream as checked by `CheckRestoreData`: stream id, match status, connector or window id it resolves to.
+type RestoreCheck = (u32, String, String);
+
+pub const CONTROL_BUS_NAME: &str = "com.hol.Kagayaku";
+pub const CONTROL_OBJECT_PATH: &str = "/com/hol/Kagayaku";
+
+/// Out-of-band control interface for tray indicators and scripts.
+pub(super) struct Control {
+  config: Arc<Config>,
+  title_normalizer: Arc<TitleNormalizer>,
+  ui_tx: Sender<ToUiMessage>,
+  sessions: Arc<Mutex<HashMap<HandleToken, ScreencastSession>>>,
+  display_state_tracker: Arc<Mutex<DisplayStateTracker>>,
+  window_state_tracker: Arc<Mutex<WindowStateTracker>>,
+  allow_dump: bool,
+}
+
+impl Control {
+  pub(super) fn new(
+    config: Arc<Config>,
+    title_normalizer: Arc<TitleNormalizer>,
+    ui_tx: Sender<ToUiMessage>,
+    sessions: Arc<Mutex<HashMap<HandleToken, ScreencastSession>>>,
+    display_state_tracker: Arc<Mutex<DisplayStateTracker>>,
+    window_state_tracker: Arc<Mutex<WindowStateTracker>>,
+    allow_dump: bool,
+  ) -> Self {
+    Self {
+      config,
+      title_normalizer,
+      ui_tx,
+      sessions,
+      display_state_tracker,
+      window_state_tracker,
+      allow_dump,
+    }
+  }
+}
+
+#[interface(name = "com.hol.Kagayaku")]
+impl Control {
+  /// Stops the screencast of the session with the given handle token.
+  pub(super) async fn stop_session(&self, token: &str) -> fdo::Result<()> {
+    let Some(session_token) = find_session_token(&self.sessions, token).await else {
+      return Err(fdo::Error::InvalidArgs(format!("unknown session token: {}", token)));
+    };
+
+    tracing::info!("stopping session {} on external request", token);
+    close_session(&self.sessions, &self.ui_tx, &session_token)
+      .await
+      .map_err(|e| fdo::Error::Failed(format!("failed to stop session: {:#}", e)))
+  }
+
+  /// Returns and logs what the trackers currently hold, for debugging restore issues. `busctl --json=pretty call`
+  /// prints the result as JSON.
+  async fn dump_state(&self) -> fdo::Result<(Vec<MonitorDump>, Vec<WindowDump>)> {
+    if !self.allow_dump {
+      return Err(fdo::Error::AccessDenied(
+        "dumping state is disabled, set trackers.allow-dump to enable it".into(),
+      ));
+    }
+
+    let monitors = self
+      .display_state_tracker
+      .lock()
+      .await
+      .monitors()
+      .values()
+      .map(|m| {
+        let (x, y) = m.position();
+        let (width, height) = m.size.unwrap_or_default();
+        (m.match_string(), m.connector.clone(), x, y, width, height, m.scale)
+      })
+      .collect::<Vec<_>>();
+    let windows = self
+      .window_state_tracker
+      .lock()
+      .await
+      .windows()
+      .iter()
+      .map(|(id, w)| (*id, w.app_id.clone(), w.title.clone()))
+      .collect::<Vec<_>>();
+
+    for (match_string, connector, x, y, width, height, scale) in &monitors {
+      tracing::info!(%match_string, %connector, x, y, width, height, scale, "tracked monitor");
+    }
+    for (id, app_id, title) in &windows {
+      tracing::info!(id, %app_id, %title, "tracked window");
+    }
+
+    Ok((monitors, windows))
+  }
+
+  /// Matches restore data against the current monitors and windows without starting a screencast, for checking what
+  /// a restore token would share. Takes the version and data of the token's restore data tuple.
+  async fn check_restore_data(&self, version: u32, data: OwnedValue) -> fdo::Result<Vec<RestoreCheck>> {
+    if !self.allow_dump {
+      return Err(fdo::Error::AccessDenied(
+        "checking restore data is disabled, set trackers.allow-dump to enable it".into(),
+      ));
+    }
+
+    let Some(restore_data) = RestoreData::decode(version, &data) else {
+      return Err(fdo::Error::InvalidArgs(format!(
+        "not valid restore data of version {}",
+        version
+      )));
+    };
+
+    tracing::info!(
+      created_at = restore_data.created_at,
+      last_used_at = restore_data.last_used_at,
+      "checking restore data"
+    );
+    let resolved = resolve_stored_streams(
+      &self.config.restore,
+      &self.title_normalizer,
+      &self.display_state_tracker,
+      &self.window_state_tracker,
+      &restore_data.streams,
+    )
+    .await;
+
+    Ok(
+      resolved
+        .into_iter()
+        .map(|(id, stream, quality)| {
+          let source = match stream {
+            Some(ScreencastStream::Monitor { connector, .. }) => connector,
+            Some(ScreencastStream::Window { window_id, .. }) => window_id.to_string(),
+            Some(ScreencastStream::Virtual { .. }) => "virtual".into(),
+            None => String::new(),
+          };
+          tracing::info!(stream = id, status = quality.as_str(), %source, "checked restore data");
+          (id, quality.as_str().to_string(), source)
+        })
+        .collect(),
+    )
+  }
+}
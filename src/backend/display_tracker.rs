@@ -1,9 +1,33 @@
-use std::collections::HashMap;
+use std::{
+  collections::{HashMap, HashSet},
+  time::{Duration, Instant},
+};
 
 use anyhow::{Context, Error as AnyError};
-use zbus::Connection;
+use zbus::{Connection, Error as ZbusError};
 
-use super::generated::org_gnome_mutter_displayconfig::DisplayConfigProxy;
+use super::{
+  drain_pending,
+  generated::org_gnome_mutter_displayconfig::{DisplayConfigProxy, MonitorsChangedStream},
+};
+
+/// Color mode of a monitor, as reported by the `color-mode` property. Mutter only exposes this since GNOME 47; older
+/// versions never report it and every monitor is treated as SDR.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+  #[default]
+  Default,
+  Bt2100,
+}
+
+impl From<u32> for ColorMode {
+  fn from(value: u32) -> Self {
+    match value {
+      1 => Self::Bt2100,
+      _ => Self::Default,
+    }
+  }
+}
 
 #[derive(Clone)]
 pub struct Monitor {
@@ -14,9 +38,54 @@ pub struct Monitor {
   pub display_name: Option<String>,
   pub builtin: bool,
   pub size: Option<(i32, i32)>,
+  /// Refresh rate of the current mode in Hz.
+  pub refresh_rate: Option<f64>,
+  /// Position of the logical monitor showing this monitor in the global layout, (0, 0) when it is not part of it.
+  pub x: i32,
+  pub y: i32,
+  /// Scale of the logical monitor showing this monitor, 1.0 when it is not part of the layout.
+  pub scale: f64,
+  /// Whether the logical monitor showing this monitor is the primary one.
+  pub is_primary: bool,
+  pub color_mode: ColorMode,
+}
+
+/// Components of a monitor match string, see [`Monitor::match_string`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum MatchKey<'a> {
+  /// Monitor without EDID data, identified by its connector.
+  Connector(&'a str),
+  Edid {
+    vendor: &'a str,
+    product: &'a str,
+    serial: &'a str,
+  },
+}
+
+impl<'a> MatchKey<'a> {
+  /// Splits a match string back into its components. Product names may contain colons themselves, vendor codes and
+  /// serials do not, so the first and last colon are the separators.
+  pub fn parse(match_string: &'a str) -> Self {
+    match match_string
+      .split_once(':')
+      .and_then(|(vendor, rest)| Some((vendor, rest.rsplit_once(':')?)))
+    {
+      Some((vendor, (product, serial))) => Self::Edid {
+        vendor,
+        product,
+        serial,
+      },
+      None => Self::Connector(match_string),
+    }
+  }
 }
 
 impl Monitor {
+  /// Identifies the monitor in restore data as `vendor:product:serial`, or as its connector when all three are
+  /// `unknown`, e.g. for virtual monitors.
+  ///
+  /// This format is stored in restore tokens, changing it breaks every saved choice. [`MatchKey::parse`] splits it
+  /// back up.
   pub fn match_string(&self) -> String {
     if self.vendor == "unknown" && self.product == "unknown" && self.serial == "unknown" {
       self.connector.to_string()
@@ -24,29 +93,109 @@ impl Monitor {
       format!("{}:{}:{}", self.vendor, self.product, self.serial)
     }
   }
+
+  /// How confident we are, from 0.0 to 1.0, that `match_string` taken from restore data refers to this monitor.
+  ///
+  /// Vendor, product and serial are compared separately, so a panel whose product string or serial number is reported
+  /// differently than before still scores well: a matching serial adds 0.4, the product 0.35 and the vendor 0.25, the
+  /// latter only along with one of the others. The connector does not count, [`DisplayStateTracker::find_monitor`]
+  /// only uses it to break ties. Monitors without EDID data are stored by connector, which only ever matches exactly.
+  pub fn match_score(&self, match_string: &str) -> f64 {
+    if self.match_string() == match_string {
+      return 1.0;
+    }
+
+    let (vendor, product, serial) = match MatchKey::parse(match_string) {
+      MatchKey::Connector(connector) => return if self.connector == connector { 1.0 } else { 0.0 },
+      MatchKey::Edid {
+        vendor,
+        product,
+        serial,
+      } => (vendor, product, serial),
+    };
+
+    // a serial is the closest thing to a unique id, the vendor alone says little and only counts when something else
+    // matches too
+    let mut score = 0.0;
+    if serial == self.serial && serial != "unknown" {
+      score += 0.4;
+    }
+    if product == self.product && product != "unknown" {
+      score += 0.35;
+    }
+    if score > 0.0 && vendor == self.vendor {
+      score += 0.25;
+    }
+
+    score
+  }
+
+  /// Whether the monitor has a current mode. Monitors that are off or in standby have none and only produce black
+  /// frames.
+  pub fn is_active(&self) -> bool {
+    self.size.is_some()
+  }
+
+  /// Size of the current mode divided by the scale, i.e. the size the monitor takes up in the global layout.
+  pub fn logical_size(&self) -> Option<(i32, i32)> {
+    self.size.map(|(w, h)| {
+      (
+        (f64::from(w) / self.scale).round() as i32,
+        (f64::from(h) / self.scale).round() as i32,
+      )
+    })
+  }
+
+  pub fn position(&self) -> (i32, i32) {
+    (self.x, self.y)
+  }
+
+  pub fn is_hdr(&self) -> bool {
+    self.color_mode == ColorMode::Bt2100
+  }
 }
 
 pub struct DisplayStateTracker {
   proxy: DisplayConfigProxy<'static>,
+  changed_stream: MonitorsChangedStream,
+  last_refresh: Instant,
   monitors: HashMap<String, Monitor>,
 }
 
 impl DisplayStateTracker {
   pub async fn new(conn: &Connection) -> Result<Self, AnyError> {
-    let proxy = DisplayConfigProxy::new(conn).await?;
-    let mut tracker = Self {
-      proxy,
-      monitors: HashMap::new(),
-    };
+    let mut tracker = Self::empty(conn).await?;
     tracker.refresh().await.context("failed to fetch display state")?;
 
     Ok(tracker)
   }
 
+  /// Creates a tracker without fetching the display state, for when DisplayConfig is unavailable.
+  pub async fn empty(conn: &Connection) -> Result<Self, AnyError> {
+    let proxy = DisplayConfigProxy::new(conn).await?;
+    let changed_stream = proxy.receive_monitors_changed().await?;
+
+    Ok(Self {
+      proxy,
+      changed_stream,
+      last_refresh: Instant::now(),
+      monitors: HashMap::new(),
+    })
+  }
+
   pub async fn refresh(&mut self) -> Result<(), AnyError> {
+    // whatever changed so far is picked up by this refresh
+    drain_pending(&mut self.changed_stream);
     let mut monitors = HashMap::new();
 
-    let (_, monitors_data, _, _) = self.proxy.get_current_state().await?;
+    let (_, monitors_data, logical_monitors, _) = self.proxy.get_current_state().await?;
+
+    let mut layout = HashMap::new();
+    for (x, y, scale, _, primary, logical_monitor_monitors, _) in logical_monitors {
+      for (connector, ..) in logical_monitor_monitors {
+        layout.insert(connector, (x, y, scale, primary));
+      }
+    }
 
     for ((connector, vendor, product, serial), modes, props) in monitors_data {
       let display_name = props
@@ -56,10 +205,17 @@ impl DisplayStateTracker {
       let builtin = props
         .get("is-builtin")
         .is_some_and(|v| v.downcast_ref().unwrap_or(false));
-      let size = modes
+      let current_mode = modes
         .iter()
-        .find(|(_, _, _, _, _, _, p)| p.get("is-current").is_some_and(|v| v.downcast_ref().unwrap_or(false)))
-        .map(|(_, w, h, _, _, _, _)| (*w, *h));
+        .find(|(_, _, _, _, _, _, p)| p.get("is-current").is_some_and(|v| v.downcast_ref().unwrap_or(false)));
+      let size = current_mode.map(|(_, w, h, _, _, _, _)| (*w, *h));
+      let refresh_rate = current_mode.map(|(_, _, _, r, _, _, _)| *r);
+      let color_mode = props
+        .get("color-mode")
+        .and_then(|v| v.downcast_ref::<u32>().ok())
+        .map(ColorMode::from)
+        .unwrap_or_default();
+      let (x, y, scale, is_primary) = layout.get(&connector).copied().unwrap_or((0, 0, 1.0, false));
 
       monitors.insert(
         connector.to_string(),
@@ -71,20 +227,242 @@ impl DisplayStateTracker {
           display_name,
           builtin,
           size,
+          refresh_rate,
+          x,
+          y,
+          scale,
+          is_primary,
+          color_mode,
         },
       );
     }
 
     self.monitors = monitors;
+    self.last_refresh = Instant::now();
 
     Ok(())
   }
 
-  pub fn find_monitor(&self, match_string: &str) -> Option<&Monitor> {
-    self.monitors.values().find(|m| m.match_string() == match_string)
+  /// The monitor scoring highest for `match_string`, if it scores at least `threshold`.
+  ///
+  /// Identical monitors, e.g. ones reporting empty or duplicate serials, score the same, the one still plugged into
+  /// `connector` wins then.
+  pub fn find_monitor(&self, match_string: &str, connector: Option<&str>, threshold: f64) -> Option<&Monitor> {
+    self.find_unclaimed_monitor(match_string, connector, threshold, &HashSet::new())
+  }
+
+  /// Like [`Self::find_monitor`], but never returns a monitor whose connector is in `claimed`, so several stored
+  /// streams cannot all resolve to the same monitor.
+  pub fn find_unclaimed_monitor(
+    &self,
+    match_string: &str,
+    connector: Option<&str>,
+    threshold: f64,
+    claimed: &HashSet<String>,
+  ) -> Option<&Monitor> {
+    self
+      .monitors
+      .values()
+      .filter(|m| !claimed.contains(&m.connector))
+      .map(|m| (m, m.match_score(match_string)))
+      .filter(|(_, score)| *score >= threshold)
+      .max_by(|(a, a_score), (b, b_score)| {
+        a_score
+          .total_cmp(b_score)
+          .then_with(|| (connector == Some(a.connector.as_str())).cmp(&(connector == Some(b.connector.as_str()))))
+      })
+      .map(|(m, _)| m)
+  }
+
+  /// A separate stream of `MonitorsChanged` signals, for waiting on changes without holding the tracker.
+  pub async fn subscribe_changes(&self) -> Result<MonitorsChangedStream, ZbusError> {
+    self.proxy.receive_monitors_changed().await
+  }
+
+  /// Whether `MonitorsChanged` was emitted since the last call. Only looks at signals already queued, so it never
+  /// waits for one.
+  pub fn has_changed(&mut self) -> bool {
+    drain_pending(&mut self.changed_stream)
+  }
+
+  pub fn is_stale(&self, max_age: Duration) -> bool {
+    self.last_refresh.elapsed() >= max_age
+  }
+
+  pub fn primary_monitor(&self) -> Option<&Monitor> {
+    self.monitors.values().find(|m| m.is_primary)
   }
 
   pub fn monitors(&self) -> &HashMap<String, Monitor> {
     &self.monitors
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex as StdMutex};
+
+  use async_global_executor::block_on;
+
+  use super::*;
+  use crate::backend::testing::{GnomeState, Services, connect, monitor};
+
+  /// A tracker fetching `monitors` from the fake DisplayConfig, along with the connection serving it.
+  async fn tracker(monitors: Vec<Monitor>) -> (DisplayStateTracker, Connection) {
+    let state = Arc::new(StdMutex::new(GnomeState {
+      monitors,
+      ..Default::default()
+    }));
+    let (client, server) = connect(&state, Services::default()).await;
+
+    (DisplayStateTracker::new(&client).await.unwrap(), server)
+  }
+
+  /// `(x, y, width, height)` the monitor takes up in the global layout.
+  fn rect(monitor: &Monitor) -> (i32, i32, i32, i32) {
+    let (x, y) = monitor.position();
+    let (width, height) = monitor.logical_size().unwrap();
+    (x, y, width, height)
+  }
+
+  #[test]
+  fn side_by_side_monitors_do_not_overlap() {
+    block_on(async {
+      let mut left = monitor("DP-1", "DELL U2720Q", "A", Some((3840, 2160)));
+      left.scale = 2.0;
+      let mut right = monitor("DP-2", "DELL U2520D", "B", Some((2560, 1440)));
+      right.x = 1920;
+      let (tracker, _server) = tracker(vec![left, right]).await;
+
+      let left = rect(&tracker.monitors()["DP-1"]);
+      let right = rect(&tracker.monitors()["DP-2"]);
+      assert_eq!(left, (0, 0, 1920, 1080));
+      assert_eq!(right, (1920, 0, 2560, 1440));
+      let overlap_x = left.0 < right.0 + right.2 && right.0 < left.0 + left.2;
+      let overlap_y = left.1 < right.1 + right.3 && right.1 < left.1 + left.3;
+      assert!(!(overlap_x && overlap_y));
+    });
+  }
+
+  #[test]
+  fn serial_outweighs_product_outweighs_vendor() {
+    let monitor = monitor("DP-1", "DELL U2720Q", "ABC123", Some((3840, 2160)));
+
+    assert_eq!(monitor.match_score("DEL:DELL U2720Q:ABC123"), 1.0);
+    // serial changed, e.g. reported differently after a firmware update
+    assert_eq!(monitor.match_score("DEL:DELL U2720Q:XYZ789"), 0.35 + 0.25);
+    // product changed, e.g. a different cable or adapter reporting another name
+    assert_eq!(monitor.match_score("DEL:DELL U2720QM:ABC123"), 0.4 + 0.25);
+    assert_eq!(monitor.match_score("GSM:LG ULTRAFINE:ABC123"), 0.4);
+    assert_eq!(monitor.match_score("DEL:DELL P2419H:XYZ789"), 0.0);
+  }
+
+  #[test]
+  fn changed_serial_or_product_still_restores() {
+    block_on(async {
+      let (tracker, _server) = tracker(vec![
+        monitor("DP-1", "DELL U2720Q", "NEWSERIAL", Some((3840, 2160))),
+        monitor("DP-2", "DELL U2720QM", "ABC123", Some((3840, 2160))),
+        monitor("HDMI-A-1", "DELL P2419H", "XYZ789", Some((1920, 1080))),
+      ])
+      .await;
+
+      // matches DP-1 on product and DP-2 on serial, the serial weighs more
+      let found = tracker.find_monitor("DEL:DELL U2720Q:ABC123", None, 0.6);
+      assert_eq!(found.map(|m| m.connector.as_str()), Some("DP-2"));
+      // only the product is left to match
+      let found = tracker.find_monitor("DEL:DELL U2720Q:OLDSERIAL", Some("HDMI-A-1"), 0.6);
+      assert_eq!(found.map(|m| m.connector.as_str()), Some("DP-1"));
+      assert!(tracker.find_monitor("DEL:DELL S2721Q:OTHER", None, 0.6).is_none());
+    });
+  }
+
+  #[test]
+  fn fractional_scale_is_kept() {
+    block_on(async {
+      let mut scaled = monitor("eDP-1", "0x095f", "0x00000000", Some((2560, 1440)));
+      scaled.scale = 1.25;
+      let (tracker, _server) = tracker(vec![scaled]).await;
+
+      let monitor = &tracker.monitors()["eDP-1"];
+      assert_eq!(monitor.scale, 1.25);
+      assert_eq!(monitor.logical_size(), Some((2048, 1152)));
+    });
+  }
+
+  fn edid(vendor: &str, product: &str, serial: &str) -> Monitor {
+    let mut monitor = monitor("Virtual-1", product, serial, Some((1920, 1080)));
+    monitor.vendor = vendor.to_string();
+    monitor
+  }
+
+  #[test]
+  fn match_string_falls_back_to_connector_without_edid() {
+    assert_eq!(edid("unknown", "unknown", "unknown").match_string(), "Virtual-1");
+  }
+
+  #[test]
+  fn match_string_keeps_partially_unknown_edid() {
+    assert_eq!(
+      edid("DEL", "DELL U2720Q", "unknown").match_string(),
+      "DEL:DELL U2720Q:unknown"
+    );
+    assert_eq!(
+      edid("unknown", "unknown", "ABC123").match_string(),
+      "unknown:unknown:ABC123"
+    );
+  }
+
+  #[test]
+  fn match_string_joins_full_edid() {
+    assert_eq!(
+      edid("DEL", "DELL U2720Q", "ABC123").match_string(),
+      "DEL:DELL U2720Q:ABC123"
+    );
+  }
+
+  #[test]
+  fn match_key_parses_what_match_string_produced() {
+    let cases = [
+      edid("unknown", "unknown", "unknown"),
+      edid("DEL", "DELL U2720Q", "unknown"),
+      edid("DEL", "DELL U2720Q", "ABC123"),
+      // product names may contain colons themselves
+      edid("SAM", "Odyssey G9: 49\"", "H4ZR"),
+    ];
+
+    for monitor in cases {
+      let match_string = monitor.match_string();
+      let expected = if monitor.vendor == "unknown" && monitor.product == "unknown" && monitor.serial == "unknown" {
+        MatchKey::Connector(&monitor.connector)
+      } else {
+        MatchKey::Edid {
+          vendor: &monitor.vendor,
+          product: &monitor.product,
+          serial: &monitor.serial,
+        }
+      };
+      assert_eq!(MatchKey::parse(&match_string), expected);
+    }
+  }
+
+  #[test]
+  fn identical_panels_are_told_apart_by_connector() {
+    block_on(async {
+      // cheap panels often report the same serial, or none at all
+      let (tracker, _server) = tracker(vec![
+        monitor("DP-1", "DELL U2720Q", "0x00000000", Some((3840, 2160))),
+        monitor("DP-2", "DELL U2720Q", "0x00000000", Some((3840, 2160))),
+      ])
+      .await;
+      let match_string = tracker.monitors()["DP-1"].match_string();
+
+      for connector in ["DP-1", "DP-2"] {
+        let found = tracker.find_monitor(&match_string, Some(connector), 0.6);
+        assert_eq!(found.map(|m| m.connector.as_str()), Some(connector));
+      }
+      // without a stored connector either of them will do
+      assert!(tracker.find_monitor(&match_string, None, 0.6).is_some());
+    });
+  }
+}
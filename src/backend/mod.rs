@@ -1,16 +1,27 @@
+pub mod capabilities;
+mod compositor;
+mod control;
 pub mod display_tracker;
+mod mutter;
+mod notifications;
+mod properties;
+mod restore;
+mod selection_cache;
+#[cfg(test)]
+mod testing;
 pub mod window_tracker;
 
 use std::{
-  collections::HashMap,
-  future::pending,
+  collections::{HashMap, HashSet},
+  process::exit,
   sync::{
     Arc,
     atomic::{AtomicU32, Ordering},
   },
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Error as AnyError};
+use anyhow::{Context, Error as AnyError, bail};
 use ashpd::{
   AppID, PortalError, WindowIdentifierType,
   backend::{
@@ -27,30 +38,36 @@ use ashpd::{
   },
   enumflags2::BitFlags,
 };
-use async_channel::{Sender, unbounded};
+use async_channel::{Receiver, Sender, unbounded};
+use async_io::Timer;
 use async_lock::Mutex;
+use async_signal::{Signal, Signals};
 use futures_util::{
-  StreamExt,
+  FutureExt, Stream, StreamExt,
+  future::{BoxFuture, Either, select},
   task::{FutureObj, Spawn, SpawnError},
 };
-use tracing::instrument;
+use tracing::{Instrument, instrument};
 use zbus::{
   Connection, Error as ZbusError,
-  fdo::RequestNameFlags,
-  zvariant::{Array, OwnedObjectPath, OwnedValue, Signature, Structure, Value},
+  fdo::{DBusProxy, RequestNameFlags},
+  zvariant::{Array, OwnedValue, Signature, Structure, Value},
 };
 
 use crate::{
   backend::{
+    capabilities::Capabilities,
+    compositor::{Compositor, CompositorSession, SessionOptions, StreamRestoreData},
+    control::{CONTROL_BUS_NAME, CONTROL_OBJECT_PATH, Control},
     display_tracker::DisplayStateTracker,
-    generated::{
-      org_gnome_mutter_screencast::ScreenCastProxy,
-      org_gnome_mutter_screencast_session::SessionProxy,
-      org_gnome_mutter_screencast_stream::{PipeWireStreamAddedStream, StreamProxy},
-    },
+    mutter::MutterCompositor,
+    notifications::Notifier,
+    restore::{RestoreData, RestoreMatch, TitleNormalizer, title_distance},
+    selection_cache::{CachedStream, SelectionCache},
     window_tracker::WindowStateTracker,
   },
-  common::{PopupData, ScreencastStreamChoice, ToBackendMessage, ToUiMessage},
+  common::{ControlMessage, ParentWindow, PopupData, ScreencastStreamChoice, ToBackendMessage, ToUiMessage},
+  config::{Config, DefaultCursorMode, RestoreConfig, RestoreOverflow},
 };
 
 mod generated {
@@ -58,7 +75,7 @@ mod generated {
 }
 
 const RESTORE_DATA_PROVIDER: &str = "Kagayaku";
-const RESTORE_DATA_VERSION: u32 = 1;
+const RESTORE_DATA_VERSION: u32 = 2;
 
 struct GlobalExecutorSpawner;
 
@@ -69,171 +86,114 @@ impl Spawn for GlobalExecutorSpawner {
   }
 }
 
-pub async fn backend_main(tx: Sender<ToUiMessage>) -> Result<(), AnyError> {
-  Builder::new("org.freedesktop.impl.portal.desktop.kagayaku")
+pub async fn backend_main(
+  tx: Sender<ToUiMessage>,
+  control_rx: Receiver<ControlMessage>,
+  config: Arc<Config>,
+) -> Result<(), AnyError> {
+  let startup = config.startup.clone();
+  let ui_tx = tx.clone();
+  let screencast_backend = ScreencastBackend::new(tx, config).await?;
+  let sessions = screencast_backend.sessions.clone();
+  if let Err(e) = screencast_backend.serve_control().await {
+    tracing::warn!("failed to serve control interface: {}", e);
+  }
+  screencast_backend.spawn_control_listener(control_rx);
+
+  let connection = screencast_backend.connection.clone();
+  spawn_name_lost_listener(&connection).await?;
+  let flags = RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue | RequestNameFlags::ReplaceExisting;
+  let mut result = Builder::new(PORTAL_BUS_NAME)
     .context("failed to create builder")?
-    .with_flags(RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue | RequestNameFlags::ReplaceExisting)
+    .with_flags(flags)
     .with_spawn(GlobalExecutorSpawner)
-    .screencast(ScreencastBackend::new(tx).await?)
-    .build()
-    .await
-    .context("failed to build DBus backend")?;
-
-  tracing::info!("starting backend loop");
-
-  pending().await
-}
-
-pub enum GnomeStreamRestoreData {
-  Monitor { match_string: String },
-  Window { app_id: String, title: String },
-}
-
-struct GnomeStream {
-  id: u32,
-  pipewire_node_id: Option<u32>,
-  source_type: SourceType,
-  position: Option<(i32, i32)>,
-  size: Option<(i32, i32)>,
-  mapping_id: Option<String>,
-  added_stream: PipeWireStreamAddedStream,
-  restore_data: GnomeStreamRestoreData,
-}
-
-pub struct GnomeSession {
-  proxy: SessionProxy<'static>,
-  streams: Vec<GnomeStream>,
-}
-
-impl GnomeSession {
-  pub async fn new(connection: &Connection, object_path: OwnedObjectPath) -> Result<Self, ZbusError> {
-    let proxy = SessionProxy::new(connection, object_path).await?;
+    .screencast(screencast_backend)
+    .build_with_connection(connection.clone())
+    .await;
+
+  // the interfaces are already served at this point, so only the name request needs to be retried
+  let mut attempt = 0;
+  while matches!(result, Err(PortalError::ZBus(ZbusError::NameTaken))) && attempt < startup.name_retries {
+    attempt += 1;
+    tracing::warn!(
+      "{} is owned by another instance, retrying in {}s ({}/{})",
+      PORTAL_BUS_NAME,
+      startup.name_retry_delay,
+      attempt,
+      startup.name_retries
+    );
+    Timer::after(Duration::from_secs(startup.name_retry_delay)).await;
+    result = connection
+      .request_name_with_flags(PORTAL_BUS_NAME, flags)
+      .await
+      .map(|_| ())
+      .map_err(PortalError::ZBus);
+  }
 
-    Ok(Self {
-      proxy,
-      streams: Vec::new(),
-    })
+  match result {
+    Err(PortalError::ZBus(ZbusError::NameTaken)) => bail!(
+      "{} is owned by another kagayaku or portal backend instance that does not allow replacement",
+      PORTAL_BUS_NAME
+    ),
+    result => result.context("failed to build DBus backend")?,
   }
 
-  pub async fn start(&mut self) -> Result<(), ZbusError> {
-    self.proxy.start().await?;
+  let mut signals = Signals::new([Signal::Term, Signal::Int]).context("failed to install signal handlers")?;
+  tracing::info!("starting backend loop");
 
-    for stream in self.streams.iter_mut() {
-      if let Some(a) = stream.added_stream.next().await
-        && let Ok(args) = a.args()
-      {
-        stream.pipewire_node_id = Some(args.node_id);
-      };
+  while let Some(signal) = signals.next().await {
+    match signal {
+      Ok(signal) => {
+        tracing::info!("received {:?}, shutting down", signal);
+        break;
+      }
+      Err(e) => tracing::warn!("failed to receive signal: {}", e),
     }
-
-    Ok(())
   }
 
-  pub async fn stop(&self) -> Result<(), ZbusError> {
-    self.proxy.stop().await
+  // mutter only cleans up after a peer that went away once it notices, which leaves the recording indicator around
+  // for a while, so stop the running sessions ourselves
+  let session_tokens = sessions.lock().await.keys().cloned().collect::<Vec<_>>();
+  for session_token in session_tokens {
+    if let Err(e) = close_session(&sessions, &ui_tx, &session_token).await {
+      tracing::warn!("failed to stop session {}: {:#}", session_token, e);
+    }
   }
 
-  pub async fn record_monitor(
-    &mut self,
-    connection: &Connection,
-    id: u32,
-    connector: String,
-    match_string: String,
-    cursor_mode: CursorMode,
-  ) -> Result<(), ZbusError> {
-    let mut props = HashMap::new();
-    let cursor_mode_value = (cursor_mode as u32).into();
-    props.insert("cursor-mode", &cursor_mode_value);
-
-    let object_path = self.proxy.record_monitor(&connector, props).await?;
-    self
-      .new_stream(
-        connection,
-        id,
-        SourceType::Monitor,
-        object_path,
-        GnomeStreamRestoreData::Monitor { match_string },
-      )
-      .await?;
-
-    Ok(())
-  }
+  exit(0)
+}
 
-  pub async fn record_window(
-    &mut self,
-    connection: &Connection,
-    id: u32,
-    window_id: u64,
-    app_id: String,
-    title: String,
-    cursor_mode: CursorMode,
-  ) -> Result<(), ZbusError> {
-    let mut props = HashMap::new();
-    let window_id_value = window_id.into();
-    let cursor_mode_value = (cursor_mode as u32).into();
-    props.insert("window-id", &window_id_value);
-    props.insert("cursor-mode", &cursor_mode_value);
-
-    let object_path = self.proxy.record_window(props).await?;
-    self
-      .new_stream(
-        connection,
-        id,
-        SourceType::Window,
-        object_path,
-        GnomeStreamRestoreData::Window { app_id, title },
-      )
-      .await?;
+const PORTAL_BUS_NAME: &str = "org.freedesktop.impl.portal.desktop.kagayaku";
 
-    Ok(())
-  }
+/// Exits once another instance takes over [`PORTAL_BUS_NAME`]. The control name lives on the same connection, so
+/// `NameLost` for anything else must not bring the portal down.
+async fn spawn_name_lost_listener(connection: &Connection) -> Result<(), AnyError> {
+  let mut name_lost = DBusProxy::new(connection)
+    .await
+    .context("failed to create DBus proxy")?
+    .receive_name_lost()
+    .await
+    .context("failed to subscribe to NameLost")?;
 
-  async fn new_stream(
-    &mut self,
-    connection: &Connection,
-    id: u32,
-    source_type: SourceType,
-    object_path: OwnedObjectPath,
-    restore_data: GnomeStreamRestoreData,
-  ) -> Result<(), ZbusError> {
-    let proxy = StreamProxy::new(connection, object_path).await?;
-    let added_stream = proxy.receive_pipe_wire_stream_added().await?;
-    let (position, size, mapping_id) = match proxy.parameters().await {
-      Ok(parameters) => {
-        let position = parameters
-          .get("position")
-          .and_then(|v| v.downcast_ref::<(i32, i32)>().ok());
-        let size = parameters.get("size").and_then(|v| v.downcast_ref::<(i32, i32)>().ok());
-        let mapping_id = parameters
-          .get("mapping-id")
-          .and_then(|v| v.downcast_ref::<&str>().ok())
-          .map(|s| s.to_string())
-          .or_else(|| {
-            parameters
-              .get("mapping-id")
-              .and_then(|v| v.downcast_ref::<String>().ok())
-          });
-        (position, size, mapping_id)
-      }
-      Err(e) => {
-        tracing::warn!("failed to fetch stream parameters: {}", e);
-        (None, None, None)
+  async_global_executor::spawn(async move {
+    while let Some(signal) = name_lost.next().await {
+      let Ok(args) = signal.args() else {
+        continue;
+      };
+      if is_portal_name(args.name()) {
+        tracing::info!("{} was taken over by another instance, exiting", PORTAL_BUS_NAME);
+        exit(0);
       }
-    };
+      tracing::warn!(name = %args.name(), "lost bus name");
+    }
+  })
+  .detach();
 
-    self.streams.push(GnomeStream {
-      id,
-      pipewire_node_id: None,
-      source_type,
-      position,
-      size,
-      mapping_id,
-      added_stream,
-      restore_data,
-    });
+  Ok(())
+}
 
-    Ok(())
-  }
+fn is_portal_name(name: &str) -> bool {
+  name == PORTAL_BUS_NAME
 }
 
 #[derive(Clone)]
@@ -242,12 +202,17 @@ pub enum ScreencastStream {
     id: u32,
     connector: String,
     match_string: String,
+    cursor_mode: Option<CursorMode>,
   },
   Window {
     id: u32,
     window_id: u64,
     app_id: String,
     title: String,
+    cursor_mode: Option<CursorMode>,
+  },
+  Virtual {
+    id: u32,
   },
 }
 
@@ -256,8 +221,8 @@ struct ScreencastSession {
   cursor_mode: CursorMode,
   source_type: BitFlags<SourceType>,
   persist_mode: PersistMode,
-  gnome_session: Option<GnomeSession>,
-  restore_data: Option<OwnedValue>,
+  compositor_session: Option<Box<dyn CompositorSession>>,
+  restore_data: Option<RestoreData>,
 }
 
 impl Default for ScreencastSession {
@@ -267,40 +232,320 @@ impl Default for ScreencastSession {
       cursor_mode: CursorMode::Hidden,
       source_type: SourceType::Monitor.into(),
       persist_mode: PersistMode::DoNot,
-      gnome_session: None,
+      compositor_session: None,
       restore_data: None,
     }
   }
 }
 
 pub struct ScreencastBackend {
+  config: Arc<Config>,
+  capabilities: Capabilities,
+  title_normalizer: Arc<TitleNormalizer>,
   ui_tx: Sender<ToUiMessage>,
   connection: Connection,
   display_state_tracker: Arc<Mutex<DisplayStateTracker>>,
   window_state_tracker: Arc<Mutex<WindowStateTracker>>,
   sessions: Arc<Mutex<HashMap<HandleToken, ScreencastSession>>>,
-  mutter_screencast_proxy: ScreenCastProxy<'static>,
+  compositor: Box<dyn Compositor>,
+  notifier: Option<Notifier>,
+  selection_cache: Option<Mutex<SelectionCache>>,
   counter: AtomicU32,
 }
 
 impl ScreencastBackend {
-  pub async fn new(ui_tx: Sender<ToUiMessage>) -> Result<Self, AnyError> {
+  pub async fn new(ui_tx: Sender<ToUiMessage>, config: Arc<Config>) -> Result<Self, AnyError> {
     let connection = Connection::session().await?;
-    let display_state_tracker = Mutex::new(DisplayStateTracker::new(&connection).await?).into();
-    let window_state_tracker = Mutex::new(WindowStateTracker::new(&connection).await?).into();
-    let sessions = Mutex::new(HashMap::new()).into();
-    let mutter_screencast_proxy = ScreenCastProxy::new(&connection).await?;
+    let capabilities = Capabilities::probe(&connection).await;
+    let compositor =
+      Box::new(MutterCompositor::new(&connection, &config.mutter, capabilities.screencast_version).await?);
+
+    Self::with_compositor(connection, capabilities, compositor, ui_tx, config).await
+  }
+
+  /// Sets up the trackers and everything else around `compositor`, degrading `capabilities` for the services that
+  /// turn out to be unusable.
+  async fn with_compositor(
+    connection: Connection,
+    mut capabilities: Capabilities,
+    compositor: Box<dyn Compositor>,
+    ui_tx: Sender<ToUiMessage>,
+    config: Arc<Config>,
+  ) -> Result<Self, AnyError> {
+    let title_normalizer: Arc<_> =
+      TitleNormalizer::new(&config.restore.title_patterns, config.restore.max_title_length).into();
+    let display_state_tracker = match DisplayStateTracker::new(&connection).await {
+      Ok(t) => t,
+      Err(e) => {
+        tracing::warn!("display state is unavailable, disabling monitor sharing: {:#}", e);
+        capabilities.display_config = false;
+        DisplayStateTracker::empty(&connection).await?
+      }
+    };
+    let display_state_tracker: Arc<_> = Mutex::new(display_state_tracker).into();
+    if config.auto_accept.enabled {
+      tracing::warn!("auto-accept is enabled, every screencast request shares a monitor without asking");
+    }
+    // shell configurations that restrict introspection reject GetWindows for us
+    let window_state_tracker = match WindowStateTracker::new(&connection).await {
+      Ok(t) => t,
+      Err(e) => {
+        tracing::warn!("window state is unavailable, disabling window sharing: {:#}", e);
+        capabilities.introspect = false;
+        WindowStateTracker::empty(&connection).await?
+      }
+    };
+    let window_state_tracker: Arc<_> = Mutex::new(window_state_tracker).into();
+    let sessions: Arc<_> = Mutex::new(HashMap::new()).into();
+    let notifier = if config.ui.notify_on_start {
+      Notifier::new(&connection, sessions.clone(), ui_tx.clone())
+        .await
+        .inspect_err(|e| tracing::warn!("notifications are unavailable: {}", e))
+        .ok()
+    } else {
+      None
+    };
+    let selection_cache = if config.restore.cache_selections {
+      SelectionCache::load(config.restore.cache_size).map(Mutex::new)
+    } else {
+      None
+    };
+
+    if capabilities.display_config {
+      match display_state_tracker.lock().await.subscribe_changes().await {
+        Ok(changes) => spawn_display_tracker_listener(display_state_tracker.clone(), changes),
+        Err(e) => tracing::warn!("failed to listen for monitor changes: {}", e),
+      }
+    }
+    if capabilities.introspect {
+      match window_state_tracker.lock().await.subscribe_changes().await {
+        Ok(changes) => spawn_window_tracker_listener(window_state_tracker.clone(), changes),
+        Err(e) => tracing::warn!("failed to listen for window changes: {}", e),
+      }
+    }
+    if let Some(interval) = config.trackers.poll_interval.filter(|i| *i > 0) {
+      spawn_tracker_poll(
+        display_state_tracker.clone(),
+        window_state_tracker.clone(),
+        Duration::from_secs(interval),
+      );
+    }
 
     Ok(Self {
+      config,
+      capabilities,
+      title_normalizer,
       ui_tx,
       connection,
       display_state_tracker,
       window_state_tracker,
       sessions,
-      mutter_screencast_proxy,
+      compositor,
+      notifier,
+      selection_cache,
       counter: AtomicU32::new(0),
     })
   }
+
+  async fn serve_control(&self) -> Result<(), ZbusError> {
+    self
+      .connection
+      .object_server()
+      .at(CONTROL_OBJECT_PATH, self.control())
+      .await?;
+    self.connection.request_name(CONTROL_BUS_NAME).await?;
+
+    Ok(())
+  }
+
+  fn control(&self) -> Control {
+    Control::new(
+      self.config.clone(),
+      self.title_normalizer.clone(),
+      self.ui_tx.clone(),
+      self.sessions.clone(),
+      self.display_state_tracker.clone(),
+      self.window_state_tracker.clone(),
+      cfg!(debug_assertions) || self.config.trackers.allow_dump,
+    )
+  }
+
+  fn spawn_control_listener(&self, control_rx: Receiver<ControlMessage>) {
+    let sessions = self.sessions.clone();
+    let ui_tx = self.ui_tx.clone();
+
+    async_global_executor::spawn(async move {
+      while let Ok(msg) = control_rx.recv().await {
+        match msg {
+          ControlMessage::StopSession(token) => {
+            let Some(session_token) = find_session_token(&sessions, &token).await else {
+              tracing::debug!("session {} is already gone", token);
+              continue;
+            };
+            tracing::info!("stopping session {} on UI request", token);
+            if let Err(e) = close_session(&sessions, &ui_tx, &session_token).await {
+              tracing::warn!("failed to stop session {}: {}", token, e);
+            }
+          }
+        }
+      }
+    })
+    .detach();
+  }
+}
+
+async fn find_session_token(
+  sessions: &Mutex<HashMap<HandleToken, ScreencastSession>>,
+  token: &str,
+) -> Option<HandleToken> {
+  sessions.lock().await.keys().find(|t| t.to_string() == token).cloned()
+}
+
+/// Consumes every item already queued on `stream` without waiting for new ones, returning whether there was any.
+fn drain_pending<S: Stream + Unpin>(stream: &mut S) -> bool {
+  let mut pending = false;
+  while let Some(Some(_)) = stream.next().now_or_never() {
+    pending = true;
+  }
+
+  pending
+}
+
+/// How long change signals have to stay quiet before a tracker is refreshed, so a burst of them causes one refresh.
+const TRACKER_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Waits for a signal on `stream` and for the burst it starts to settle. Returns `false` once the stream has ended.
+async fn next_settled<S: Stream + Unpin>(stream: &mut S) -> bool {
+  if stream.next().await.is_none() {
+    return false;
+  }
+
+  loop {
+    match select(stream.next(), Timer::after(TRACKER_DEBOUNCE)).await {
+      Either::Left((Some(_), _)) => {}
+      // refresh for the burst we already saw, the next call reports the end
+      Either::Left((None, _)) | Either::Right(_) => return true,
+    }
+  }
+}
+
+/// Refreshes the display tracker whenever Mutter reports the monitors changed, so hotplugged monitors show up in the
+/// picker and restore matches against the current monitors.
+fn spawn_display_tracker_listener(
+  display_state_tracker: Arc<Mutex<DisplayStateTracker>>,
+  mut changes: impl Stream + Unpin + Send + 'static,
+) {
+  async_global_executor::spawn(async move {
+    while next_settled(&mut changes).await {
+      if let Err(e) = display_state_tracker.lock().await.refresh().await {
+        tracing::warn!("failed to refresh display state: {}", e);
+      }
+    }
+    tracing::debug!("monitor change signal stream ended");
+  })
+  .detach();
+}
+
+/// Refreshes the window tracker whenever the shell reports the windows changed, so the picker opens with a current
+/// window list.
+fn spawn_window_tracker_listener(
+  window_state_tracker: Arc<Mutex<WindowStateTracker>>,
+  mut changes: impl Stream + Unpin + Send + 'static,
+) {
+  async_global_executor::spawn(async move {
+    while next_settled(&mut changes).await {
+      if let Err(e) = window_state_tracker.lock().await.refresh().await {
+        tracing::warn!("failed to refresh window state: {}", e);
+      }
+    }
+    tracing::debug!("window change signal stream ended");
+  })
+  .detach();
+}
+
+/// Periodically refreshes the trackers in case change signals get lost. Trackers refreshed within the last half
+/// interval by some other path are left alone.
+fn spawn_tracker_poll(
+  display_state_tracker: Arc<Mutex<DisplayStateTracker>>,
+  window_state_tracker: Arc<Mutex<WindowStateTracker>>,
+  interval: Duration,
+) {
+  tracing::info!("polling display and window state every {:?}", interval);
+
+  let max_age = interval / 2;
+  async_global_executor::spawn(async move {
+    let mut timer = Timer::interval(interval);
+    while timer.next().await.is_some() {
+      {
+        let mut display_state = display_state_tracker.lock().await;
+        if display_state.is_stale(max_age)
+          && let Err(e) = display_state.refresh().await
+        {
+          tracing::warn!("failed to poll display state: {}", e);
+        }
+      }
+      {
+        let mut window_state = window_state_tracker.lock().await;
+        if window_state.is_stale(max_age)
+          && let Err(e) = window_state.refresh().await
+        {
+          tracing::warn!("failed to poll window state: {}", e);
+        }
+      }
+    }
+  })
+  .detach();
+}
+
+/// Removes a session and dismisses its popup if one is open.
+async fn remove_session(
+  sessions: &Mutex<HashMap<HandleToken, ScreencastSession>>,
+  ui_tx: &Sender<ToUiMessage>,
+  session_token: &HandleToken,
+) -> Option<ScreencastSession> {
+  let session = sessions.lock().await.remove(session_token)?;
+  tracing::info!("closing session");
+  if let Err(e) = ui_tx.try_send(ToUiMessage::CloseSession(session_token.to_string())) {
+    tracing::warn!("failed to send close request to UI thread: {}", e);
+  }
+
+  Some(session)
+}
+
+/// Removes a session, dismisses its popup if one is open and stops the compositor session behind it.
+async fn close_session(
+  sessions: &Mutex<HashMap<HandleToken, ScreencastSession>>,
+  ui_tx: &Sender<ToUiMessage>,
+  session_token: &HandleToken,
+) -> Result<(), AnyError> {
+  if let Some(session) = remove_session(sessions, ui_tx, session_token).await
+    && let Some(mut compositor_session) = session.compositor_session
+  {
+    compositor_session.stop().await?
+  }
+
+  Ok(())
+}
+
+/// Forgets a session once the compositor ends it on its own. Sessions stopped by us are already gone by the time
+/// the compositor reports them closed.
+fn spawn_closed_listener(
+  sessions: Arc<Mutex<HashMap<HandleToken, ScreencastSession>>>,
+  ui_tx: Sender<ToUiMessage>,
+  session_token: HandleToken,
+  closed: BoxFuture<'static, ()>,
+) {
+  let span = tracing::info_span!("compositor_closed", token = %session_token);
+  async_global_executor::spawn(
+    async move {
+      closed.await;
+      if remove_session(&sessions, &ui_tx, &session_token).await.is_some() {
+        tracing::info!("compositor ended the session");
+      }
+    }
+    .instrument(span),
+  )
+  .detach();
 }
 
 #[async_trait::async_trait]
@@ -313,22 +558,11 @@ impl RequestImpl for ScreencastBackend {
 
 #[async_trait::async_trait]
 impl SessionImpl for ScreencastBackend {
-  #[instrument(skip(self))]
+  #[instrument(skip_all, fields(token = %session_token))]
   async fn session_closed(&self, session_token: HandleToken) -> Result<(), PortalError> {
-    let mut sessions = self.sessions.lock().await;
-    if let Some(session) = sessions.remove(&session_token) {
-      tracing::info!("closing session");
-      drop(sessions);
-      if let Err(e) = self
-        .ui_tx
-        .try_send(ToUiMessage::CloseSession(session_token.to_string()))
-      {
-        tracing::warn!("failed to send close request to UI thread: {}", e);
-      }
-      if let Some(gnome_session) = session.gnome_session {
-        gnome_session.stop().await?
-      }
-    }
+    close_session(&self.sessions, &self.ui_tx, &session_token)
+      .await
+      .map_err(|e| PortalError::Failed(format!("{:#}", e)))?;
 
     Ok(())
   }
@@ -337,14 +571,31 @@ impl SessionImpl for ScreencastBackend {
 #[async_trait::async_trait]
 impl ScreencastImpl for ScreencastBackend {
   fn available_source_types(&self) -> BitFlags<SourceType> {
-    SourceType::Monitor | SourceType::Window
+    let mut source_types = BitFlags::empty();
+    if self.capabilities.monitor_recording() {
+      source_types |= SourceType::Monitor;
+    }
+    if self.capabilities.window_recording() {
+      source_types |= SourceType::Window;
+    }
+    if self.capabilities.virtual_recording() {
+      source_types |= SourceType::Virtual;
+    }
+
+    source_types
   }
 
   fn available_cursor_mode(&self) -> BitFlags<CursorMode> {
+    // mutter hides the cursor when it cannot be told otherwise
+    if !self.capabilities.cursor_modes() {
+      return CursorMode::Hidden.into();
+    }
+
     // FIXME: ashpd 0.13.0-alpha cant deserialize metadata cursor mode (value 4)
     CursorMode::Hidden | CursorMode::Embedded
   }
 
+  #[instrument(skip_all, fields(token = %session_token))]
   async fn create_session(
     &self,
     _: HandleToken,
@@ -352,24 +603,65 @@ impl ScreencastImpl for ScreencastBackend {
     _: Option<AppID>,
     _: CreateSessionOptions,
   ) -> Result<CreateSessionResponse, PortalError> {
+    let cursor_mode = match self.config.session.cursor_mode {
+      DefaultCursorMode::Hidden => CursorMode::Hidden,
+      DefaultCursorMode::Embedded => CursorMode::Embedded,
+    };
+    let session = ScreencastSession {
+      cursor_mode: if self.available_cursor_mode().contains(cursor_mode) {
+        cursor_mode
+      } else {
+        CursorMode::Hidden
+      },
+      ..Default::default()
+    };
+
     let mut sessions = self.sessions.lock().await;
-    sessions.insert(session_token.clone(), Default::default());
+    // replacing the entry would leave a running compositor session behind with nothing left to stop it
+    if sessions.contains_key(&session_token) {
+      tracing::warn!("session token is already in use");
+      return Err(PortalError::Exist(format!("session {} already exists", session_token)));
+    }
+    sessions.insert(session_token.clone(), session);
 
     Ok(CreateSessionResponse::new(session_token))
   }
 
-  // TODO: support remote desktop session
+  #[instrument(skip_all, fields(token = %session_token))]
   async fn select_sources(
     &self,
     session_token: HandleToken,
-    _: Option<AppID>,
+    app_id: Option<AppID>,
     options: SelectSourcesOptions,
   ) -> Result<SelectSourcesResponse, PortalError> {
     let mut sessions = self.sessions.lock().await;
+    // RemoteDesktop sessions never reach us: the frontend only hands them to backends implementing RemoteDesktop, and
+    // ashpd rejects sessions not created through this backend anyway. An unknown token is just a bad argument.
     let Some(session) = sessions.get_mut(&session_token) else {
       return Err(PortalError::InvalidArgument("unknown session token".into()));
     };
 
+    // validate everything before touching the session, so a rejected call leaves it as it was
+    if let Some(c) = options.cursor_mode()
+      && !self.available_cursor_mode().contains(c)
+    {
+      return Err(PortalError::InvalidArgument(format!("unsupported cursor mode {:?}", c)));
+    }
+    let source_type = match options.sources() {
+      Some(s) => {
+        let requested = if s.is_empty() { SourceType::Monitor.into() } else { s };
+        let supported = requested & self.available_source_types();
+        if supported.is_empty() {
+          return Err(PortalError::InvalidArgument(format!(
+            "none of the requested source types {:?} are supported",
+            requested
+          )));
+        }
+        Some(supported)
+      }
+      None => None,
+    };
+
     if let Some(m) = options.is_multiple() {
       session.multiple = m;
     }
@@ -379,22 +671,33 @@ impl ScreencastImpl for ScreencastBackend {
     if let Some(p) = options.persist_mode() {
       session.persist_mode = p;
     }
-    if let Some(s) = options.sources() {
-      session.source_type = if s.is_empty() { SourceType::Monitor.into() } else { s };
+    if let Some(s) = source_type {
+      session.source_type = s;
     }
 
     if session.persist_mode != PersistMode::DoNot
       && let Some((provider, version, data)) = options.restore_data()
       && provider == RESTORE_DATA_PROVIDER
-      && version == RESTORE_DATA_VERSION
+      && version <= RESTORE_DATA_VERSION
     {
-      session.restore_data = Some(data.try_to_owned().unwrap());
+      session.restore_data = RestoreData::decode(version, data);
+    }
+    // clients that drop their restore data still get the last selection back
+    if session.persist_mode != PersistMode::DoNot
+      && session.restore_data.is_none()
+      && let Some(cache) = self.selection_cache.as_ref()
+      && let Some(app_id) = app_id.as_deref().filter(|a| !a.is_empty())
+    {
+      session.restore_data = cache.lock().await.get(app_id);
+      if session.restore_data.is_some() {
+        tracing::debug!(%app_id, "no restore data from the client, using the cached selection");
+      }
     }
 
     Ok(SelectSourcesResponse {})
   }
 
-  #[instrument(skip(self), fields(app_id = ?client_app_id))]
+  #[instrument(skip_all, fields(token = %session_token, app_id = ?client_app_id))]
   async fn start_cast(
     &self,
     session_token: HandleToken,
@@ -404,68 +707,164 @@ impl ScreencastImpl for ScreencastBackend {
   ) -> Result<Streams, PortalError> {
     tracing::info!("starting screencast session");
 
+    let app_id = client_app_id.map(|i| i.to_string());
+
     let sessions = self.sessions.lock().await;
     let Some(session) = sessions.get(&session_token) else {
       return Err(PortalError::InvalidArgument("unknown session token".into()));
     };
 
-    let session_path = self.mutter_screencast_proxy.create_session(HashMap::new()).await?;
-    let mut gnome_session = GnomeSession::new(&self.connection, session_path).await?;
-
     let source_type = session.source_type;
-    let restored_streams = if session.persist_mode != PersistMode::DoNot
+    let multiple = session.multiple;
+    let (restored_streams, expected_streams, expected_monitors, created_at) = if session.persist_mode
+      != PersistMode::DoNot
       && let Some(d) = session.restore_data.as_ref()
     {
-      if let Ok((_, _, a)) = d.downcast_ref::<(i64, i64, Array)>() {
-        self.restore_streams(a.iter()).await
-      } else {
-        tracing::debug!("unknown restore data");
+      // tokens written before timestamps were tracked carry 0
+      let created_at = Some(d.created_at).filter(|c| *c > 0);
+      let expected_monitors = d
+        .streams
+        .iter()
+        .filter(|(_, t, _)| *t == SourceType::Monitor as u32)
+        .count();
+      (
+        self.restore_streams(&d.streams).await,
+        d.streams.len(),
+        expected_monitors,
+        created_at,
+      )
+    } else {
+      (None, 0, 0, None)
+    };
+    // restored streams that end up in the picker anyway are offered as its initial selection
+    let mut preselected_streams = Vec::new();
+    let mut partial_restore = None;
+    let restored_streams = match restored_streams {
+      Some(mut s) if !multiple && s.len() > 1 => match self.config.restore.overflow {
+        RestoreOverflow::Truncate => {
+          tracing::info!(
+            restored = s.len(),
+            "single stream requested, keeping the first restored stream"
+          );
+          s.truncate(1);
+          Some(s)
+        }
+        RestoreOverflow::Prompt => {
+          tracing::info!(
+            restored = s.len(),
+            "single stream requested, prompting instead of restoring"
+          );
+          s.truncate(1);
+          preselected_streams = s;
+          None
+        }
+      },
+      Some(s) if s.len() < expected_streams => {
+        tracing::info!(
+          restored = s.len(),
+          expected = expected_streams,
+          "some restored sources are missing, prompting for them"
+        );
+        partial_restore = Some((s.len(), expected_streams));
+        preselected_streams = s;
         None
       }
-    } else {
-      None
+      None if expected_streams > 0 => {
+        partial_restore = Some((0, expected_streams));
+        None
+      }
+      s => s,
     };
-    let multiple = session.multiple;
     let persist_mode = session.persist_mode;
+    // a stored monitor that cannot be found is most likely replaced by a different one, offer the primary instead
+    let restored_monitors = preselected_streams
+      .iter()
+      .filter(|s| matches!(s, ScreencastStream::Monitor { .. }))
+      .count();
+    let fallback_to_primary =
+      partial_restore.is_some() && restored_monitors < expected_monitors && source_type.contains(SourceType::Monitor);
 
     // drop while running the UI
     drop(sessions);
 
-    let (remember, prompted_streams) = if restored_streams.is_none() {
+    let disable_animations = self.config.session.disable_animations;
+    let auto_accept = restored_streams.is_none() && self.config.auto_accept.enabled;
+    let (remember, disable_animations, prompted_streams) = if auto_accept {
+      if !source_type.contains(SourceType::Monitor) {
+        return Err(PortalError::NotAllowed(
+          "auto-accept only shares monitors, but the client did not ask for one".into(),
+        ));
+      }
+      tracing::warn!("auto-accept is enabled, sharing without asking");
+      (false, disable_animations, self.auto_accept_streams().await?)
+    } else if restored_streams.is_none() {
       let (tx, rx) = unbounded();
-      let (monitors, windows) = {
+      let (monitors, windows, window_order, primary_monitor) = {
         let mut display_state = self.display_state_tracker.lock().await;
         let mut window_state = self.window_state_tracker.lock().await;
 
         if let Err(e) = display_state.refresh().await {
           tracing::warn!("failed to refresh display state: {}", e);
         }
-        if let Err(e) = window_state.refresh().await {
+        if self.capabilities.introspect
+          && let Err(e) = window_state.refresh().await
+        {
           tracing::warn!("failed to refresh window state: {}", e);
         }
 
-        (display_state.monitors().clone(), window_state.windows().clone())
+        (
+          display_state.monitors().clone(),
+          window_state.windows().clone(),
+          window_state.focus_order(),
+          display_state.primary_monitor().map(|m| m.connector.clone()),
+        )
       };
+      let mut preselected_monitors: Vec<_> = preselected_streams
+        .iter()
+        .filter_map(|s| match s {
+          ScreencastStream::Monitor { connector, .. } => Some(connector.clone()),
+          _ => None,
+        })
+        .collect();
+      if fallback_to_primary
+        && (multiple || preselected_streams.is_empty())
+        && let Some(primary) = primary_monitor
+        && !preselected_monitors.contains(&primary)
+      {
+        preselected_monitors.push(primary);
+      }
 
       let popup_data = PopupData {
         session_token: session_token.to_string(),
-        app_id: client_app_id.map(|i| i.to_string()),
-        parent_window: window_identifier.and_then(|ty| {
-          if let WindowIdentifierType::Wayland(id) = ty {
-            Some(id)
-          } else {
-            None
-          }
+        app_id: app_id.clone(),
+        parent_window: window_identifier.map(|ty| match ty {
+          WindowIdentifierType::Wayland(handle) => ParentWindow::Wayland(handle),
+          WindowIdentifierType::X11(xid) => ParentWindow::X11(xid),
         }),
         backend_tx: tx,
         multiple,
         source_type,
+        cursor_modes: self.available_cursor_mode(),
+        disable_animations: self.capabilities.disable_animations().then_some(disable_animations),
         persist_mode,
         monitors,
         windows,
+        window_order,
+        preselected_monitors,
+        preselected_windows: preselected_streams
+          .iter()
+          .filter_map(|s| match s {
+            ScreencastStream::Window { window_id, .. } => Some(*window_id),
+            _ => None,
+          })
+          .collect(),
+        preselected_virtual: preselected_streams
+          .iter()
+          .any(|s| matches!(s, ScreencastStream::Virtual { .. })),
+        partial_restore,
       };
 
-      if let Err(e) = self.ui_tx.send(ToUiMessage::NewPopup(popup_data)).await {
+      if let Err(e) = self.ui_tx.send(ToUiMessage::NewPopup(popup_data.into())).await {
         tracing::warn!("failed to send UI message: {}", e);
         return Err(PortalError::Failed(format!("cannot start UI: {}", e)));
       }
@@ -473,33 +872,42 @@ impl ScreencastImpl for ScreencastBackend {
 
       match backend_msg {
         Ok(s) => match s {
-          ToBackendMessage::Success((b, v)) => {
-            tracing::info!(selected_sources = v.len(), "ui accepted screencast");
+          ToBackendMessage::Success {
+            remember,
+            disable_animations,
+            choices,
+          } => {
+            tracing::info!(selected_sources = choices.len(), "ui accepted screencast");
             let mut res = Vec::new();
-            for choice in v {
+            for choice in choices {
               let id = self.counter.fetch_add(1, Ordering::Relaxed);
               match choice {
                 ScreencastStreamChoice::Monitor {
                   connector,
                   match_string,
+                  cursor_mode,
                 } => res.push(ScreencastStream::Monitor {
                   id,
                   connector,
                   match_string,
+                  cursor_mode,
                 }),
                 ScreencastStreamChoice::Window {
                   window_id,
                   app_id,
                   title,
+                  cursor_mode,
                 } => res.push(ScreencastStream::Window {
                   id,
                   window_id,
                   app_id,
                   title,
+                  cursor_mode,
                 }),
+                ScreencastStreamChoice::Virtual => res.push(ScreencastStream::Virtual { id }),
               }
             }
-            (b, res)
+            (remember, disable_animations, res)
           }
           ToBackendMessage::Cancel => {
             tracing::info!("ui cancelled screencast");
@@ -512,32 +920,175 @@ impl ScreencastImpl for ScreencastBackend {
         }
       }
     } else {
-      (false, Vec::new())
+      // hand the restore data back so its last used time gets updated
+      (true, disable_animations, Vec::new())
     };
 
     let mut sessions = self.sessions.lock().await;
-    let session = sessions.get_mut(&session_token).unwrap();
-    let streams_iter = if let Some(s) = restored_streams.as_ref() {
-      s.iter()
+    // the session may have been stopped through the control interface while the picker was open
+    let Some(session) = sessions.get_mut(&session_token) else {
+      tracing::info!("session was closed while choosing sources");
+      return Err(PortalError::Failed(
+        "session was closed before the screencast started".into(),
+      ));
+    };
+    let streams_to_record = if let Some(s) = restored_streams.as_ref() {
+      s
     } else {
-      prompted_streams.iter()
+      &prompted_streams
     };
 
-    for stream in streams_iter {
+    let mut compositor_session = self
+      .compositor
+      .create_session(&SessionOptions { disable_animations })
+      .await
+      .map_err(|e| {
+        tracing::warn!("failed to create compositor session: {:#}", e);
+        PortalError::Failed(format!("{:#}", e))
+      })?;
+
+    // the compositor session only ends up in our session map once it is started, so clean up here on failure
+    if let Err(e) = self
+      .record_streams(compositor_session.as_mut(), session, streams_to_record)
+      .await
+    {
+      tracing::warn!("failed to record streams: {:#}", e);
+      if let Err(stop_err) = compositor_session.stop().await {
+        tracing::warn!("failed to stop compositor session: {:#}", stop_err);
+      }
+      return Err(PortalError::Failed(format!("{:#}", e)));
+    }
+
+    let mut streams = Vec::new();
+    let mut restore_data = Array::new(&Signature::try_from("uuv").unwrap());
+    let mut cached_streams = Vec::new();
+
+    let display_state = self.display_state_tracker.lock().await;
+    for stream in compositor_session.streams() {
+      if let Some(node_id) = stream.pipewire_node_id {
+        // mutter usually reports the size itself, otherwise fall back to the layout size of the recorded monitor
+        let size = match &stream.restore_data {
+          StreamRestoreData::Monitor {
+            match_string,
+            connector,
+          } if stream.size.is_none() => display_state
+            .find_monitor(match_string, Some(connector), 1.0)
+            .and_then(|m| m.logical_size()),
+          _ => stream.size,
+        };
+        let mut stream_builder = StreamBuilder::new(node_id)
+          .id(Some(stream.id.to_string()))
+          .source_type(stream.source_type);
+
+        stream_builder = stream_builder.position(stream.position);
+        stream_builder = stream_builder.size(size);
+        stream_builder = stream_builder.mapping_id(stream.mapping_id.clone());
+
+        streams.push(stream_builder.build());
+
+        if remember && session.persist_mode != PersistMode::DoNot {
+          let cached_stream = match &stream.restore_data {
+            StreamRestoreData::Monitor {
+              match_string,
+              connector,
+            } => CachedStream::Monitor {
+              id: stream.id,
+              match_string: match_string.to_string(),
+              connector: connector.to_string(),
+            },
+            StreamRestoreData::Window { app_id, title } => CachedStream::Window {
+              id: stream.id,
+              app_id: app_id.to_string(),
+              title: self.title_normalizer.normalize(title),
+            },
+            StreamRestoreData::Virtual => CachedStream::Virtual { id: stream.id },
+          };
+
+          restore_data.append(cached_stream.restore_data().into()).unwrap();
+          cached_streams.push(cached_stream);
+        }
+      }
+    }
+    drop(display_state);
+
+    if streams.is_empty() {
+      tracing::warn!(
+        recorded_streams = compositor_session.streams().len(),
+        "no stream received a PipeWire node"
+      );
+      if let Err(e) = compositor_session.stop().await {
+        tracing::warn!("failed to stop compositor session: {:#}", e);
+      }
+      return Err(PortalError::Failed("no screencast stream could be started".into()));
+    }
+
+    let mut resp = StreamsBuilder::new(streams);
+    // restore data holds the creation and last use times in seconds since the epoch
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs() as i64);
+    let created_at = if restored_streams.is_some() {
+      created_at.unwrap_or(now)
+    } else {
+      now
+    };
+
+    if remember && session.persist_mode != PersistMode::DoNot {
+      resp = resp.restore_data(Some((
+        RESTORE_DATA_PROVIDER.to_string(),
+        RESTORE_DATA_VERSION,
+        Value::from((created_at, now, restore_data)).try_into_owned().unwrap(),
+      )));
+
+      if let Some(cache) = self.selection_cache.as_ref()
+        && let Some(app_id) = app_id.as_deref().filter(|a| !a.is_empty())
+      {
+        cache.lock().await.store(app_id, created_at, now, cached_streams);
+      }
+    }
+
+    if let Some(closed) = compositor_session.take_closed() {
+      spawn_closed_listener(self.sessions.clone(), self.ui_tx.clone(), session_token.clone(), closed);
+    }
+    session.compositor_session = Some(compositor_session);
+
+    if let Some(notifier) = self.notifier.as_ref() {
+      notifier.notify_started(&session_token, app_id.as_deref()).await;
+    }
+    if let Err(e) = self
+      .ui_tx
+      .try_send(ToUiMessage::SessionStarted(session_token.to_string(), app_id))
+    {
+      tracing::warn!("failed to notify UI thread about started session: {}", e);
+    }
+
+    Ok(resp.build())
+  }
+}
+
+impl ScreencastBackend {
+  /// Records `streams` on `compositor_session` and starts it.
+  async fn record_streams(
+    &self,
+    compositor_session: &mut dyn CompositorSession,
+    session: &ScreencastSession,
+    streams: &[ScreencastStream],
+  ) -> Result<(), AnyError> {
+    for stream in streams {
       match stream {
         ScreencastStream::Monitor {
           id,
           connector,
           match_string,
+          cursor_mode,
         } => {
           if session.source_type.contains(SourceType::Monitor) {
-            gnome_session
+            compositor_session
               .record_monitor(
-                &self.connection,
                 *id,
                 connector.to_string(),
                 match_string.to_string(),
-                session.cursor_mode,
+                cursor_mode.unwrap_or(session.cursor_mode),
               )
               .await?;
           }
@@ -547,137 +1098,1083 @@ impl ScreencastImpl for ScreencastBackend {
           window_id,
           app_id,
           title,
+          cursor_mode,
         } => {
           if session.source_type.contains(SourceType::Window) {
-            gnome_session
+            compositor_session
               .record_window(
-                &self.connection,
                 *id,
                 *window_id,
                 app_id.to_string(),
                 title.to_string(),
-                session.cursor_mode,
+                cursor_mode.unwrap_or(session.cursor_mode),
               )
               .await?;
           }
         }
+        ScreencastStream::Virtual { id } => {
+          if session.source_type.contains(SourceType::Virtual) {
+            compositor_session.record_virtual(*id, session.cursor_mode).await?;
+          }
+        }
       }
     }
 
-    gnome_session.start().await?;
+    compositor_session.start().await
+  }
 
-    let mut streams = Vec::new();
-    let mut restore_data = Array::new(&Signature::try_from("uuv").unwrap());
+  /// The monitor auto-accept shares: the configured connector, otherwise the primary monitor.
+  async fn auto_accept_streams(&self) -> Result<Vec<ScreencastStream>, PortalError> {
+    let mut display_state = self.display_state_tracker.lock().await;
+    if let Err(e) = display_state.refresh().await {
+      tracing::warn!("failed to refresh display state: {}", e);
+    }
 
-    for stream in gnome_session.streams.iter() {
-      if let Some(node_id) = stream.pipewire_node_id {
-        let mut stream_builder = StreamBuilder::new(node_id)
-          .id(Some(stream.id.to_string()))
-          .source_type(stream.source_type);
+    let monitor = match self.config.auto_accept.connector.as_ref() {
+      Some(connector) => display_state.monitors().get(connector),
+      None => display_state.primary_monitor(),
+    };
+    let Some(monitor) = monitor.filter(|m| m.is_active()) else {
+      return Err(PortalError::Failed("no monitor to auto-accept".into()));
+    };
+    tracing::info!(connector = %monitor.connector, "auto-accepting monitor");
+
+    Ok(vec![ScreencastStream::Monitor {
+      id: self.counter.fetch_add(1, Ordering::Relaxed),
+      connector: monitor.connector.to_string(),
+      match_string: monitor.match_string(),
+      cursor_mode: None,
+    }])
+  }
 
-        stream_builder = stream_builder.position(stream.position);
-        stream_builder = stream_builder.size(stream.size);
-        stream_builder = stream_builder.mapping_id(stream.mapping_id.clone());
+  async fn restore_streams(&self, stored_streams: &[(u32, u32, OwnedValue)]) -> Option<Vec<ScreencastStream>> {
+    // stream ids are the per-stream keys of the restore token, keep newly picked streams from reusing them
+    for (id, _, _) in stored_streams {
+      self.counter.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+    }
 
-        streams.push(stream_builder.build());
+    let streams = resolve_stored_streams(
+      &self.config.restore,
+      &self.title_normalizer,
+      &self.display_state_tracker,
+      &self.window_state_tracker,
+      stored_streams,
+    )
+    .await
+    .into_iter()
+    .filter_map(|(_, stream, _)| stream)
+    .collect::<Vec<_>>();
 
-        if remember && session.persist_mode != PersistMode::DoNot {
-          let stream_data = match &stream.restore_data {
-            GnomeStreamRestoreData::Monitor { match_string } => Value::from(match_string.to_string()),
-            GnomeStreamRestoreData::Window { app_id, title } => Value::from((app_id.to_string(), title.to_string())),
-          };
+    if streams.is_empty() { None } else { Some(streams) }
+  }
+}
+
+/// Matches every stored stream against the current monitors and windows, refreshing the trackers first if they are
+/// behind. Has no side effects, so it also serves to check restore data without starting a screencast.
+async fn resolve_stored_streams(
+  config: &RestoreConfig,
+  title_normalizer: &TitleNormalizer,
+  display_state_tracker: &Mutex<DisplayStateTracker>,
+  window_state_tracker: &Mutex<WindowStateTracker>,
+  stored_streams: &[(u32, u32, OwnedValue)],
+) -> Vec<(u32, Option<ScreencastStream>, RestoreMatch)> {
+  let mut resolved = Vec::new();
+  let mut display_state = display_state_tracker.lock().await;
+  let mut window_state = window_state_tracker.lock().await;
+
+  if display_state.has_changed()
+    && let Err(e) = display_state.refresh().await
+  {
+    tracing::warn!("failed to refresh display state: {}", e);
+  }
+  if window_state.has_changed()
+    && let Err(e) = window_state.refresh().await
+  {
+    tracing::warn!("failed to refresh window state: {}", e);
+  }
+
+  // a monitor or window is shared at most once, even if several stored streams match it
+  let mut claimed_monitors = HashSet::new();
+  let mut claimed_windows = HashSet::new();
+  for (id, source_type, data) in stored_streams {
+    let (id, source_type) = (*id, *source_type);
+
+    let (stream, quality) = match source_type {
+      v if v == SourceType::Monitor as u32 => {
+        let Some((match_string, connector)) = data
+          .downcast_ref::<Structure>()
+          .ok()
+          .and_then(|s| <(String, String)>::try_from(s).ok())
+        else {
+          resolved.push((id, None, RestoreMatch::Invalid));
+          continue;
+        };
+        // streams upgraded from v1 restore data do not know their connector
+        let connector = Some(connector.as_str()).filter(|c| !c.is_empty());
 
-          restore_data
-            .append((stream.id, stream.source_type as u32, stream_data).into())
-            .unwrap();
+        match display_state.find_unclaimed_monitor(
+          &match_string,
+          connector,
+          config.monitor_match_threshold,
+          &claimed_monitors,
+        ) {
+          Some(monitor) => {
+            claimed_monitors.insert(monitor.connector.clone());
+            (
+              Some(ScreencastStream::Monitor {
+                id,
+                connector: monitor.connector.to_string(),
+                match_string: monitor.match_string(),
+                cursor_mode: None,
+              }),
+              if monitor.match_score(&match_string) >= 1.0 {
+                RestoreMatch::Exact
+              } else {
+                RestoreMatch::Fuzzy
+              },
+            )
+          }
+          None => (None, RestoreMatch::NotFound),
         }
       }
-    }
+      v if v == SourceType::Window as u32 => {
+        let Some((app_id, title)) = data
+          .downcast_ref::<Structure>()
+          .ok()
+          .and_then(|s| <(String, String)>::try_from(s).ok())
+        else {
+          resolved.push((id, None, RestoreMatch::Invalid));
+          continue;
+        };
+
+        let normalized_title = title_normalizer.normalize(&title);
+        let app_windows = window_state.windows_for_app(&app_id).count();
+        let candidates = window_state
+          .windows_for_app(&app_id)
+          .filter(|(wid, _)| !claimed_windows.contains(wid))
+          .map(|(wid, window)| {
+            let distance = title_distance(&normalized_title, &title_normalizer.normalize(&window.title));
+            (wid, distance)
+          })
+          .collect::<Vec<_>>();
+        let best = candidates.iter().min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let matched = match best {
+          Some((wid, distance)) if *distance == 0.0 => Some((*wid, RestoreMatch::Exact)),
+          Some((wid, distance)) if *distance <= config.title_match_threshold => Some((*wid, RestoreMatch::Fuzzy)),
+          // titles churn too much for some apps, but a lone window of the same app is still the one we want
+          Some((wid, _)) if app_windows == 1 => Some((*wid, RestoreMatch::Fuzzy)),
+          _ => None,
+        };
+        match matched {
+          Some((window_id, quality)) => {
+            claimed_windows.insert(window_id);
+            (
+              Some(ScreencastStream::Window {
+                id,
+                window_id,
+                app_id,
+                title,
+                cursor_mode: None,
+              }),
+              quality,
+            )
+          }
+          None => (None, RestoreMatch::NotFound),
+        }
+      }
+      // nothing to match, restoring it just creates a new one
+      v if v == SourceType::Virtual as u32 => (Some(ScreencastStream::Virtual { id }), RestoreMatch::Exact),
+      v => {
+        tracing::debug!("unknown source type: {}", v);
+        (None, RestoreMatch::Invalid)
+      }
+    };
+    resolved.push((id, stream, quality));
+  }
 
-    let mut resp = StreamsBuilder::new(streams);
+  resolved
+}
 
-    if remember && session.persist_mode != PersistMode::DoNot {
-      resp = resp.restore_data(Some((
-        RESTORE_DATA_PROVIDER.to_string(),
-        RESTORE_DATA_VERSION,
-        // we currently dont use timestamp
-        Value::from((0, 0, restore_data)).try_into_owned().unwrap(),
-      )));
+#[cfg(test)]
+mod tests {
+  use std::{pin::pin, time::Instant};
+
+  use async_global_executor::block_on;
+  use futures_util::{future::pending, stream};
+  use zbus::zvariant::{LE, serialized::Context, to_bytes};
+
+  use super::*;
+  use crate::backend::{
+    display_tracker::Monitor,
+    testing::{GnomeState, MockCompositor, Services, TestBackend, emit_monitors_changed, monitor, window},
+  };
+
+  const APP: &str = "org.example.Recorder";
+
+  fn token(token: &str) -> HandleToken {
+    HandleToken::try_from(token).unwrap()
+  }
+
+  fn app_id() -> Option<AppID> {
+    Some(AppID::try_from(APP).unwrap())
+  }
+
+  async fn create_session(test: &TestBackend, session: &str) -> Result<CreateSessionResponse, PortalError> {
+    test
+      .backend
+      .create_session(
+        token("request"),
+        token(session),
+        app_id(),
+        CreateSessionOptions::default(),
+      )
+      .await
+  }
+
+  async fn select_sources(
+    test: &TestBackend,
+    session: &str,
+    options: SelectSourcesOptions,
+  ) -> Result<SelectSourcesResponse, PortalError> {
+    test.backend.select_sources(token(session), app_id(), options).await
+  }
+
+  /// Runs `start_cast`, answering the picker with `answer` if it opens. The popup data is handed back along with
+  /// the result, `None` when no picker was shown.
+  async fn start_cast(
+    test: &TestBackend,
+    session: &str,
+    answer: impl FnOnce(&PopupData) -> ToBackendMessage,
+  ) -> (Result<Streams, PortalError>, Option<PopupData>) {
+    let cast = pin!(
+      test
+        .backend
+        .start_cast(token(session), app_id(), None, StartCastOptions::default())
+    );
+    let picker = pin!(async {
+      while let Ok(msg) = test.ui_rx.recv().await {
+        if let ToUiMessage::NewPopup(popup) = msg {
+          popup.backend_tx.send(answer(&popup)).await.unwrap();
+          return popup;
+        }
+      }
+      pending().await
+    });
+
+    match select(cast, picker).await {
+      Either::Left((result, _)) => (result, None),
+      Either::Right((popup, cast)) => (cast.await, Some(*popup)),
     }
+  }
 
-    session.gnome_session = Some(gnome_session);
+  /// Select sources options as the portal frontend sends them, the only way restore data can be handed in.
+  fn options(multiple: bool, restore_data: Option<(&str, u32, Value<'static>)>) -> SelectSourcesOptions {
+    let mut options = HashMap::from([
+      ("multiple", Value::from(multiple)),
+      ("persist_mode", Value::from(PersistMode::Application as u32)),
+      ("types", Value::from((SourceType::Monitor | SourceType::Window).bits())),
+    ]);
+    if let Some((provider, version, data)) = restore_data {
+      options.insert("restore_data", Value::from((provider.to_string(), version, data)));
+    }
 
-    Ok(resp.build())
+    to_bytes(Context::new_dbus(LE, 0), &options)
+      .unwrap()
+      .deserialize()
+      .unwrap()
+      .0
   }
-}
 
-impl ScreencastBackend {
-  async fn restore_streams<'a>(&'a self, iter: impl Iterator<Item = &'a Value<'a>>) -> Option<Vec<ScreencastStream>> {
-    let mut streams = Vec::new();
-    let mut display_state = self.display_state_tracker.lock().await;
-    let mut window_state = self.window_state_tracker.lock().await;
+  /// Restore data in the current format holding `streams`.
+  fn restore_data(streams: &[CachedStream]) -> Option<(&'static str, u32, Value<'static>)> {
+    let mut data = Array::new(&Signature::try_from("uuv").unwrap());
+    for stream in streams {
+      data.append(stream.restore_data().into()).unwrap();
+    }
 
-    if let Err(e) = display_state.refresh().await {
-      tracing::warn!("failed to refresh display state: {}", e);
+    Some((
+      RESTORE_DATA_PROVIDER,
+      RESTORE_DATA_VERSION,
+      Value::from((1_i64, 1_i64, data)),
+    ))
+  }
+
+  fn stored_monitor(id: u32, monitor: &Monitor) -> CachedStream {
+    CachedStream::Monitor {
+      id,
+      match_string: monitor.match_string(),
+      connector: monitor.connector.clone(),
     }
-    if let Err(e) = window_state.refresh().await {
-      tracing::warn!("failed to refresh window state: {}", e);
+  }
+
+  fn two_monitors() -> GnomeState {
+    GnomeState {
+      monitors: vec![
+        monitor("DP-1", "DELL U2720Q", "A", Some((3840, 2160))),
+        monitor("DP-2", "DELL U2720Q", "B", Some((3840, 2160))),
+      ],
+      ..Default::default()
     }
+  }
 
-    for stream in iter {
-      let Ok((id, source_type, data)) = stream.to_owned().downcast::<(u32, u32, OwnedValue)>() else {
-        continue;
+  fn share_monitor(connector: &str, popup: &PopupData) -> ToBackendMessage {
+    ToBackendMessage::Success {
+      remember: true,
+      disable_animations: false,
+      choices: vec![ScreencastStreamChoice::Monitor {
+        connector: connector.to_string(),
+        match_string: popup.monitors[connector].match_string(),
+        cursor_mode: None,
+      }],
+    }
+  }
+
+  #[test]
+  fn start_cast_records_selected_monitor_through_mutter() {
+    block_on(async {
+      let test = TestBackend::mutter(
+        Config::default(),
+        GnomeState {
+          monitors: vec![monitor("DP-1", "DELL U2720Q", "ABC123", Some((3840, 2160)))],
+          ..Default::default()
+        },
+      )
+      .await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(
+        &test,
+        "session",
+        SelectSourcesOptions::default().set_sources(BitFlags::from(SourceType::Monitor)),
+      )
+      .await
+      .unwrap();
+      let (streams, popup) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+
+      assert!(popup.unwrap().monitors.contains_key("DP-1"));
+      let streams = streams.unwrap();
+      assert_eq!(streams.streams().len(), 1);
+      assert_eq!(streams.streams()[0].pipe_wire_node_id(), 40);
+      assert_eq!(streams.streams()[0].source_type(), Some(SourceType::Monitor));
+      assert_eq!(test.gnome().screencast.recorded_monitors, ["DP-1"]);
+      assert_eq!(test.gnome().screencast.started, 1);
+
+      test.backend.session_closed(token("session")).await.unwrap();
+      assert_eq!(test.gnome().screencast.stopped, 1);
+    });
+  }
+
+  #[test]
+  fn only_losing_portal_name_is_fatal() {
+    assert!(is_portal_name(PORTAL_BUS_NAME));
+    assert!(!is_portal_name(CONTROL_BUS_NAME));
+    assert!(!is_portal_name(":1.42"));
+  }
+
+  #[test]
+  fn restored_streams_beyond_single_stream_are_truncated() {
+    block_on(async {
+      let gnome = two_monitors();
+      let stored = [
+        stored_monitor(0, &gnome.monitors[0]),
+        stored_monitor(1, &gnome.monitors[1]),
+      ];
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), gnome, Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(false, restore_data(&stored)))
+        .await
+        .unwrap();
+      let (streams, popup) = start_cast(&test, "session", |_| ToBackendMessage::Cancel).await;
+
+      assert!(popup.is_none());
+      assert_eq!(streams.unwrap().streams().len(), 1);
+      assert_eq!(compositor.log().recorded, ["monitor DP-1"]);
+    });
+  }
+
+  #[test]
+  fn restored_streams_beyond_single_stream_prompt_when_configured() {
+    block_on(async {
+      let gnome = two_monitors();
+      let stored = [
+        stored_monitor(0, &gnome.monitors[0]),
+        stored_monitor(1, &gnome.monitors[1]),
+      ];
+      let mut config = Config::default();
+      config.restore.overflow = RestoreOverflow::Prompt;
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(config, gnome, Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(false, restore_data(&stored)))
+        .await
+        .unwrap();
+      let (streams, popup) = start_cast(&test, "session", |_| ToBackendMessage::Cancel).await;
+
+      assert_eq!(popup.unwrap().preselected_monitors, ["DP-1"]);
+      assert!(matches!(streams, Err(PortalError::Cancelled(_))));
+      assert_eq!(compositor.log().sessions, 0);
+    });
+  }
+
+  #[test]
+  fn control_stops_running_session() {
+    block_on(async {
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+      let control = test.backend.control();
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+      streams.unwrap();
+
+      control.stop_session("session").await.unwrap();
+      assert_eq!(compositor.log().stopped, 1);
+      assert!(test.backend.sessions.lock().await.is_empty());
+      assert!(matches!(
+        control.stop_session("session").await,
+        Err(zbus::fdo::Error::InvalidArgs(_))
+      ));
+    });
+  }
+
+  #[test]
+  fn session_stopped_while_picker_is_open_fails_start_cast() {
+    block_on(async {
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+      let control = test.backend.control();
+
+      create_session(&test, "session").await.unwrap();
+      // the picker answers before it hears about the stopped session
+      let picker = async {
+        let Ok(ToUiMessage::NewPopup(popup)) = test.ui_rx.recv().await else {
+          panic!("expected the picker to open");
+        };
+        control.stop_session("session").await.unwrap();
+        popup.backend_tx.send(share_monitor("DP-1", &popup)).await.unwrap();
       };
+      let (streams, ()) = futures_util::join!(
+        test
+          .backend
+          .start_cast(token("session"), app_id(), None, StartCastOptions::default()),
+        picker
+      );
+
+      assert!(matches!(streams, Err(PortalError::Failed(_))));
+      assert_eq!(compositor.log().sessions, 0);
+    });
+  }
 
-      match source_type {
-        v if v == SourceType::Monitor as u32 => {
-          let Ok(match_string) = data.downcast_ref() else {
-            continue;
-          };
+  /// Selects sources with `restore_data` and starts the cast, returning whether the picker was shown.
+  async fn restore_prompts(restore_data: Option<(&str, u32, Value<'static>)>) -> (bool, bool) {
+    let compositor = MockCompositor::default();
+    let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+
+    create_session(&test, "session").await.unwrap();
+    select_sources(&test, "session", options(true, restore_data))
+      .await
+      .unwrap();
+    let decoded = test.backend.sessions.lock().await[&token("session")]
+      .restore_data
+      .is_some();
+    let (streams, popup) = start_cast(&test, "session", |p| share_monitor("DP-2", p)).await;
+    streams.unwrap();
+
+    (decoded, popup.is_some())
+  }
 
-          if let Some(monitor) = display_state.find_monitor(match_string) {
-            streams.push(ScreencastStream::Monitor {
-              id,
-              connector: monitor.connector.to_string(),
-              match_string: monitor.match_string(),
-            });
-          }
-        }
-        v if v == SourceType::Window as u32 => {
-          let Ok(s) = data.downcast_ref::<Structure>() else {
-            continue;
-          };
-          let Ok((app_id, title)): Result<(String, String), _> = s.try_into() else {
-            continue;
-          };
+  #[test]
+  fn restore_data_of_matching_provider_and_version_is_used() {
+    block_on(async {
+      let gnome = two_monitors();
+      let stored = [stored_monitor(0, &gnome.monitors[0])];
 
-          for (wid, window) in window_state.windows().iter() {
-            if window.app_id != app_id {
-              continue;
-            }
+      assert_eq!(restore_prompts(restore_data(&stored)).await, (true, false));
+    });
+  }
 
-            // TODO: levenshtein distance search
-            if title == window.title {
-              streams.push(ScreencastStream::Window {
-                id,
-                window_id: *wid,
-                app_id,
-                title,
-              });
-              break;
-            }
-          }
-        }
-        v if v == SourceType::Virtual as u32 => {
-          continue;
+  #[test]
+  fn restore_data_of_other_provider_is_ignored() {
+    block_on(async {
+      let gnome = two_monitors();
+      let (_, version, data) = restore_data(&[stored_monitor(0, &gnome.monitors[0])]).unwrap();
+
+      assert_eq!(restore_prompts(Some(("GNOME", version, data))).await, (false, true));
+    });
+  }
+
+  #[test]
+  fn restore_data_of_newer_version_is_ignored() {
+    block_on(async {
+      let gnome = two_monitors();
+      let (provider, _, data) = restore_data(&[stored_monitor(0, &gnome.monitors[0])]).unwrap();
+
+      assert_eq!(
+        restore_prompts(Some((provider, RESTORE_DATA_VERSION + 1, data))).await,
+        (false, true)
+      );
+    });
+  }
+
+  #[test]
+  fn start_cast_without_any_node_fails_and_stops_session() {
+    block_on(async {
+      let compositor = MockCompositor {
+        no_nodes: true,
+        ..Default::default()
+      };
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+
+      assert!(matches!(streams, Err(PortalError::Failed(_))));
+      assert_eq!(compositor.log().started, 1);
+      assert_eq!(compositor.log().stopped, 1);
+      assert!(
+        test.backend.sessions.lock().await[&token("session")]
+          .compositor_session
+          .is_none()
+      );
+    });
+  }
+
+  #[test]
+  fn missing_display_config_disables_monitor_sharing() {
+    block_on(async {
+      let test = TestBackend::with_services(
+        Config::default(),
+        two_monitors(),
+        Services {
+          display_config: false,
+          ..Default::default()
+        },
+        Box::new(MockCompositor::default()),
+      )
+      .await;
+
+      let source_types = test.backend.available_source_types();
+      assert!(!source_types.contains(SourceType::Monitor));
+      assert!(source_types.contains(SourceType::Window));
+      assert!(test.backend.display_state_tracker.lock().await.monitors().is_empty());
+    });
+  }
+
+  #[test]
+  fn start_cast_fails_for_bogus_mutter_session_path() {
+    block_on(async {
+      let mut gnome = two_monitors();
+      gnome.screencast.session_path = Some("/org/example/ScreenCast/Session/u1".to_string());
+      let test = TestBackend::mutter(Config::default(), gnome).await;
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+
+      assert!(matches!(streams, Err(PortalError::Failed(_))));
+      assert_eq!(test.gnome().screencast.sessions, 1);
+      assert!(test.gnome().screencast.recorded_monitors.is_empty());
+    });
+  }
+
+  #[test]
+  fn restore_picks_streams_among_hundreds_of_windows() {
+    block_on(async {
+      let mut gnome = two_monitors();
+      for app in 0..50 {
+        for doc in 0..10 {
+          gnome.windows.push((
+            1000 + app * 10 + doc,
+            window(
+              &format!("org.example.Editor{}", app),
+              &format!("Document {} - Project {}", doc, app),
+            ),
+          ));
         }
-        v => {
-          tracing::debug!("unknown source type: {}", v);
-          continue;
+      }
+      let stored_window = |id, app, title: &str| CachedStream::Window {
+        id,
+        app_id: format!("org.example.Editor{}", app),
+        title: title.to_string(),
+      };
+      let stored = [
+        stored_monitor(0, &gnome.monitors[1]),
+        stored_window(1, 12, "(3) Document 7 - Project 12"),
+        stored_window(2, 30, "Document 4 - Project 30 (edited)"),
+        stored_window(3, 49, "Document 0 - Project 49"),
+      ];
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), gnome, Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(true, restore_data(&stored)))
+        .await
+        .unwrap();
+      let (streams, popup) = start_cast(&test, "session", |_| ToBackendMessage::Cancel).await;
+
+      assert!(popup.is_none());
+      assert_eq!(streams.unwrap().streams().len(), 4);
+      assert_eq!(
+        compositor.log().recorded,
+        ["monitor DP-2", "window 1127", "window 1304", "window 1490"]
+      );
+    });
+  }
+
+  /// Resolves stored windows of `org.example.Editor` titled `stored_titles` among `titles`, windows 1, 2 and so on.
+  async fn resolve_windows(titles: &[&str], stored_titles: &[&str]) -> Vec<(Option<u64>, RestoreMatch)> {
+    let gnome = GnomeState {
+      windows: (1..)
+        .zip(titles)
+        .map(|(id, title)| (id, window("org.example.Editor", title)))
+        .collect(),
+      ..Default::default()
+    };
+    let test = TestBackend::new(Config::default(), gnome, Box::new(MockCompositor::default())).await;
+    let stored = (0..)
+      .zip(stored_titles)
+      .map(|(id, title)| {
+        let (id, source_type, data) = CachedStream::Window {
+          id,
+          app_id: "org.example.Editor".to_string(),
+          title: title.to_string(),
         }
+        .restore_data();
+        (id, source_type, data.try_into_owned().unwrap())
+      })
+      .collect::<Vec<_>>();
+
+    resolve_stored_streams(
+      &test.backend.config.restore,
+      &test.backend.title_normalizer,
+      &test.backend.display_state_tracker,
+      &test.backend.window_state_tracker,
+      &stored,
+    )
+    .await
+    .into_iter()
+    .map(|(_, stream, quality)| match stream {
+      Some(ScreencastStream::Window { window_id, .. }) => (Some(window_id), quality),
+      _ => (None, quality),
+    })
+    .collect()
+  }
+
+  async fn resolve_window(titles: &[&str], stored_title: &str) -> (Option<u64>, RestoreMatch) {
+    resolve_windows(titles, &[stored_title]).await[0]
+  }
+
+  #[test]
+  fn window_titles_match_up_to_the_threshold() {
+    block_on(async {
+      // the default threshold is 0.3, three edits in ten characters
+      let other = "zzzzzzzzzz";
+
+      assert_eq!(
+        resolve_window(&[other, "abcdefghij"], "abcdefghij").await,
+        (Some(2), RestoreMatch::Exact)
+      );
+      assert_eq!(
+        resolve_window(&[other, "abcdefghXY"], "abcdefghij").await,
+        (Some(2), RestoreMatch::Fuzzy)
+      );
+      assert_eq!(
+        resolve_window(&[other, "abcdefgXYZ"], "abcdefghij").await,
+        (Some(2), RestoreMatch::Fuzzy)
+      );
+      assert_eq!(
+        resolve_window(&[other, "abcdefWXYZ"], "abcdefghij").await,
+        (None, RestoreMatch::NotFound)
+      );
+    });
+  }
+
+  #[test]
+  fn window_titles_are_compared_normalized() {
+    block_on(async {
+      let titles = ["(5) Inbox — Mozilla Firefox", "Calendar — Mozilla Firefox"];
+
+      assert_eq!(resolve_window(&titles, "Inbox").await, (Some(1), RestoreMatch::Exact));
+      assert_eq!(
+        resolve_window(&titles, "● Calendar").await,
+        (Some(2), RestoreMatch::Exact)
+      );
+    });
+  }
+
+  #[test]
+  fn lone_window_of_app_matches_beyond_the_threshold() {
+    block_on(async {
+      assert_eq!(
+        resolve_window(&["something else"], "abcdefghij").await,
+        (Some(1), RestoreMatch::Fuzzy)
+      );
+      assert_eq!(resolve_window(&[], "abcdefghij").await, (None, RestoreMatch::NotFound));
+    });
+  }
+
+  #[test]
+  fn drain_pending_never_waits() {
+    let mut nothing = stream::pending::<()>();
+    assert!(!drain_pending(&mut nothing));
+
+    let mut queued = stream::iter([(), (), ()]);
+    assert!(drain_pending(&mut queued));
+    assert!(!drain_pending(&mut queued));
+
+    assert!(!drain_pending(&mut stream::empty::<()>()));
+  }
+
+  #[test]
+  fn burst_of_change_signals_settles_into_one_refresh() {
+    block_on(async {
+      let (tx, rx) = unbounded();
+      let mut rx = pin!(rx);
+      for _ in 0..3 {
+        tx.send(()).await.unwrap();
       }
-    }
 
-    if streams.is_empty() { None } else { Some(streams) }
+      let started = Instant::now();
+      assert!(next_settled(&mut rx).await);
+      assert!(started.elapsed() >= TRACKER_DEBOUNCE);
+      assert!(!drain_pending(&mut rx));
+
+      // signals arriving during the debounce belong to the same burst
+      tx.send(()).await.unwrap();
+      let late = async {
+        Timer::after(TRACKER_DEBOUNCE / 2).await;
+        tx.send(()).await.unwrap();
+        drop(tx);
+      };
+      let (settled, ()) = futures_util::join!(next_settled(&mut rx), late);
+      assert!(settled);
+      assert!(!next_settled(&mut rx).await);
+    });
+  }
+
+  #[test]
+  fn failed_recording_stops_compositor_session() {
+    block_on(async {
+      let compositor = MockCompositor {
+        fail_record: true,
+        ..Default::default()
+      };
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+
+      assert!(matches!(streams, Err(PortalError::Failed(_))));
+      assert_eq!(compositor.log().sessions, 1);
+      assert_eq!(compositor.log().started, 0);
+      assert_eq!(compositor.log().stopped, 1);
+    });
+  }
+
+  #[test]
+  fn unsupported_cursor_mode_is_rejected() {
+    block_on(async {
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(MockCompositor::default())).await;
+
+      create_session(&test, "session").await.unwrap();
+      let result = select_sources(
+        &test,
+        "session",
+        SelectSourcesOptions::default()
+          .set_multiple(true)
+          .set_cursor_mode(CursorMode::Metadata),
+      )
+      .await;
+
+      assert!(matches!(result, Err(PortalError::InvalidArgument(_))));
+      let sessions = test.backend.sessions.lock().await;
+      let session = &sessions[&token("session")];
+      assert_ne!(session.cursor_mode, CursorMode::Metadata);
+      assert!(!session.multiple);
+    });
+  }
+
+  #[test]
+  fn truncated_restore_hands_back_single_stream_restore_data() {
+    block_on(async {
+      let gnome = two_monitors();
+      let stored = [
+        stored_monitor(0, &gnome.monitors[0]),
+        stored_monitor(1, &gnome.monitors[1]),
+      ];
+      let test = TestBackend::new(Config::default(), gnome, Box::new(MockCompositor::default())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(false, restore_data(&stored)))
+        .await
+        .unwrap();
+      let (streams, _) = start_cast(&test, "session", |_| ToBackendMessage::Cancel).await;
+
+      let streams = streams.unwrap();
+      let (provider, version, data) = streams.restore_data().expect("restore data is handed back");
+      assert_eq!(provider, RESTORE_DATA_PROVIDER);
+      let restored = RestoreData::decode(*version, data).unwrap();
+      assert_eq!(restored.streams.len(), 1);
+      let (id, source_type, _) = &restored.streams[0];
+      assert_eq!((*id, *source_type), (0, SourceType::Monitor as u32));
+    });
+  }
+
+  #[test]
+  fn restore_data_of_v1_is_still_restored() {
+    block_on(async {
+      let gnome = two_monitors();
+      let mut data = Array::new(&Signature::try_from("uuv").unwrap());
+      data
+        .append(Value::from((
+          0_u32,
+          SourceType::Monitor as u32,
+          Value::from(gnome.monitors[1].match_string()),
+        )))
+        .unwrap();
+
+      assert_eq!(
+        restore_prompts(Some((RESTORE_DATA_PROVIDER, 1, Value::from((1_i64, 1_i64, data))))).await,
+        (true, false)
+      );
+    });
+  }
+
+  #[test]
+  fn closing_session_stops_what_start_cast_recorded() {
+    block_on(async {
+      let mut gnome = two_monitors();
+      gnome.windows.push((7, window("org.gnome.TextEditor", "notes.txt")));
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), gnome, Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(true, None)).await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |popup| ToBackendMessage::Success {
+        remember: false,
+        disable_animations: false,
+        choices: vec![
+          ScreencastStreamChoice::Monitor {
+            connector: "DP-2".to_string(),
+            match_string: popup.monitors["DP-2"].match_string(),
+            cursor_mode: None,
+          },
+          ScreencastStreamChoice::Window {
+            window_id: 7,
+            app_id: "org.gnome.TextEditor".to_string(),
+            title: "notes.txt".to_string(),
+            cursor_mode: None,
+          },
+        ],
+      })
+      .await;
+
+      let streams = streams.unwrap();
+      assert!(streams.restore_data().is_none());
+      for stream in streams.streams() {
+        let id = stream.id().unwrap().parse::<u32>().unwrap();
+        assert_eq!(stream.pipe_wire_node_id(), 100 + id);
+      }
+      assert_eq!(compositor.log().recorded, ["monitor DP-2", "window 7"]);
+      assert_eq!(compositor.log().started, 1);
+
+      test.backend.session_closed(token("session")).await.unwrap();
+      assert_eq!(compositor.log().stopped, 1);
+      assert!(test.backend.sessions.lock().await.is_empty());
+      let mut closed = false;
+      while let Ok(msg) = test.ui_rx.try_recv() {
+        closed |= matches!(msg, ToUiMessage::CloseSession(s) if s == "session");
+      }
+      assert!(closed);
+    });
+  }
+
+  #[test]
+  fn advertised_source_types_and_cursor_modes_follow_screencast_version() {
+    block_on(async {
+      let mut test = TestBackend::new(Config::default(), two_monitors(), Box::new(MockCompositor::default())).await;
+      let cases = [
+        (
+          1,
+          BitFlags::from(SourceType::Monitor),
+          BitFlags::from(CursorMode::Hidden),
+        ),
+        (
+          2,
+          SourceType::Monitor | SourceType::Window,
+          CursorMode::Hidden | CursorMode::Embedded,
+        ),
+        (
+          3,
+          SourceType::Monitor | SourceType::Window | SourceType::Virtual,
+          CursorMode::Hidden | CursorMode::Embedded,
+        ),
+        (
+          4,
+          SourceType::Monitor | SourceType::Window | SourceType::Virtual,
+          CursorMode::Hidden | CursorMode::Embedded,
+        ),
+      ];
+
+      for (version, source_types, cursor_modes) in cases {
+        test.backend.capabilities.screencast_version = Some(version);
+        assert_eq!(
+          test.backend.available_source_types(),
+          source_types,
+          "version {}",
+          version
+        );
+        assert_eq!(
+          test.backend.available_cursor_mode(),
+          cursor_modes,
+          "version {}",
+          version
+        );
+      }
+    });
+  }
+
+  #[test]
+  fn monitor_stream_without_size_reports_logical_monitor_size() {
+    block_on(async {
+      let mut gnome = two_monitors();
+      gnome.monitors[1].scale = 2.0;
+      let test = TestBackend::new(Config::default(), gnome, Box::new(MockCompositor::default())).await;
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-2", p)).await;
+
+      let streams = streams.unwrap();
+      assert_eq!(streams.streams()[0].size(), Some((1920, 1080)));
+    });
+  }
+
+  #[test]
+  fn select_sources_for_unknown_session_is_rejected() {
+    block_on(async {
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(MockCompositor::default())).await;
+
+      let result = select_sources(&test, "unknown", SelectSourcesOptions::default()).await;
+
+      assert!(matches!(result, Err(PortalError::InvalidArgument(_))));
+    });
+  }
+
+  #[test]
+  fn monitors_changed_signal_refreshes_display_state() {
+    block_on(async {
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(MockCompositor::default())).await;
+
+      test
+        .gnome()
+        .monitors
+        .push(monitor("HDMI-A-1", "LG ULTRAFINE", "C", Some((2560, 1440))));
+      emit_monitors_changed(&test.server).await;
+
+      // the listener waits for the signals to settle before refreshing
+      let deadline = Instant::now() + Duration::from_secs(5);
+      while !test
+        .backend
+        .display_state_tracker
+        .lock()
+        .await
+        .monitors()
+        .contains_key("HDMI-A-1")
+      {
+        assert!(Instant::now() < deadline, "display state was not refreshed");
+        Timer::after(TRACKER_DEBOUNCE / 5).await;
+      }
+    });
+  }
+
+  #[test]
+  fn restricted_introspection_disables_window_sharing() {
+    block_on(async {
+      let mut gnome = two_monitors();
+      gnome.windows.push((1, window("org.gnome.TextEditor", "notes.txt")));
+      gnome.restrict_introspect = true;
+      let test = TestBackend::new(Config::default(), gnome, Box::new(MockCompositor::default())).await;
+
+      let source_types = test.backend.available_source_types();
+      assert!(!source_types.contains(SourceType::Window));
+      assert!(source_types.contains(SourceType::Monitor));
+      assert!(test.backend.window_state_tracker.lock().await.windows().is_empty());
+    });
+  }
+
+  #[test]
+  fn session_token_in_use_is_rejected() {
+    block_on(async {
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), two_monitors(), Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      let (streams, _) = start_cast(&test, "session", |p| share_monitor("DP-1", p)).await;
+      streams.unwrap();
+
+      assert!(matches!(
+        create_session(&test, "session").await,
+        Err(PortalError::Exist(_))
+      ));
+      // the running session is left alone
+      assert!(
+        test.backend.sessions.lock().await[&token("session")]
+          .compositor_session
+          .is_some()
+      );
+      assert_eq!(compositor.log().stopped, 0);
+    });
+  }
+
+  #[test]
+  fn two_stored_panels_do_not_both_restore_to_one_connected_panel() {
+    block_on(async {
+      let stored_gnome = two_monitors();
+      let stored = [
+        stored_monitor(0, &stored_gnome.monitors[0]),
+        stored_monitor(1, &stored_gnome.monitors[1]),
+      ];
+      // only the first panel is plugged in, the second would still match it on vendor and product
+      let gnome = GnomeState {
+        monitors: vec![stored_gnome.monitors[0].clone()],
+        ..Default::default()
+      };
+      let compositor = MockCompositor::default();
+      let test = TestBackend::new(Config::default(), gnome, Box::new(compositor.clone())).await;
+
+      create_session(&test, "session").await.unwrap();
+      select_sources(&test, "session", options(true, restore_data(&stored)))
+        .await
+        .unwrap();
+      let (streams, popup) = start_cast(&test, "session", |_| ToBackendMessage::Cancel).await;
+
+      let popup = popup.expect("the missing panel is prompted for");
+      assert_eq!(popup.preselected_monitors, ["DP-1"]);
+      assert_eq!(popup.partial_restore, Some((1, 2)));
+      assert!(matches!(streams, Err(PortalError::Cancelled(_))));
+      assert_eq!(compositor.log().sessions, 0);
+    });
+  }
+
+  #[test]
+  fn window_is_restored_for_at_most_one_stored_stream() {
+    block_on(async {
+      assert_eq!(
+        resolve_windows(&["notes.txt"], &["notes.txt", "notes.txt"]).await,
+        [(Some(1), RestoreMatch::Exact), (None, RestoreMatch::NotFound)]
+      );
+      // the lone window fallback does not hand out a window that is already taken either
+      assert_eq!(
+        resolve_windows(&["notes.txt"], &["notes.txt", "something else"]).await,
+        [(Some(1), RestoreMatch::Exact), (None, RestoreMatch::NotFound)]
+      );
+      assert_eq!(
+        resolve_windows(&["notes.txt", "todo.txt"], &["notes.txt", "notes.txt*"]).await,
+        [(Some(1), RestoreMatch::Exact), (None, RestoreMatch::NotFound)]
+      );
+    });
   }
 }
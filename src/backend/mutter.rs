@@ -0,0 +1,360 @@
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::{Context, Error as AnyError, bail};
+use ashpd::desktop::screencast::{CursorMode, SourceType};
+use async_io::Timer;
+use futures_util::{
+  FutureExt, StreamExt,
+  future::{BoxFuture, Either, select},
+};
+use zbus::{
+  Connection,
+  zvariant::{OwnedObjectPath, OwnedValue},
+};
+
+use crate::{
+  backend::{
+    capabilities::{CURSOR_MODE_VERSION, DISABLE_ANIMATIONS_VERSION, RECORD_VIRTUAL_VERSION, RECORD_WINDOW_VERSION},
+    compositor::{Compositor, CompositorSession, CompositorStream, SessionOptions, StreamRestoreData},
+    generated::{
+      org_gnome_mutter_screencast::ScreenCastProxy,
+      org_gnome_mutter_screencast_session::{ClosedStream, SessionProxy},
+      org_gnome_mutter_screencast_stream::{PipeWireStreamAddedStream, StreamProxy},
+    },
+    properties,
+  },
+  config::MutterConfig,
+};
+
+/// How long to wait for Mutter to announce the PipeWire nodes of a started session.
+const PIPEWIRE_NODE_TIMEOUT: Duration = Duration::from_secs(5);
+const MUTTER_SESSION_PATH_PREFIX: &str = "/org/gnome/Mutter/ScreenCast/Session/";
+
+/// Screencasting through Mutter's `org.gnome.Mutter.ScreenCast` interface.
+pub struct MutterCompositor {
+  connection: Connection,
+  version: i32,
+  proxy: ScreenCastProxy<'static>,
+  session_properties: HashMap<String, OwnedValue>,
+  record_properties: Arc<HashMap<String, OwnedValue>>,
+}
+
+impl MutterCompositor {
+  pub async fn new(
+    connection: &Connection,
+    config: &MutterConfig,
+    screencast_version: Option<i32>,
+  ) -> Result<Self, AnyError> {
+    // without the service every start_cast would fail, so refuse to run instead of claiming the portal name
+    let Some(version) = screencast_version else {
+      bail!("org.gnome.Mutter.ScreenCast is not available on the session bus, is this a GNOME session?");
+    };
+    tracing::info!(version, "using Mutter ScreenCast");
+    let proxy = ScreenCastProxy::new(connection)
+      .await
+      .context("failed to connect to Mutter ScreenCast")?;
+
+    Ok(Self {
+      connection: connection.clone(),
+      version,
+      proxy,
+      session_properties: properties::session_properties(&config.session_properties, screencast_version),
+      record_properties: properties::record_properties(&config.record_properties, screencast_version).into(),
+    })
+  }
+}
+
+#[async_trait::async_trait]
+impl Compositor for MutterCompositor {
+  async fn create_session(&self, options: &SessionOptions) -> Result<Box<dyn CompositorSession>, AnyError> {
+    let mut props: HashMap<_, _> = self
+      .session_properties
+      .iter()
+      .map(|(k, v)| (k.as_str(), &**v))
+      .collect();
+    // older versions ignore unknown properties anyway, but leave it out so the request matches what they document
+    let disable_animations_value = options.disable_animations.into();
+    if self.version >= DISABLE_ANIMATIONS_VERSION {
+      props.insert("disable-animations", &disable_animations_value);
+    }
+
+    let session_path = self
+      .proxy
+      .create_session(props)
+      .await
+      .context("Mutter failed to create a screencast session")?;
+    if !is_session_path(session_path.as_str()) {
+      bail!("mutter returned an invalid screencast session path: {}", session_path);
+    }
+
+    let session = MutterSession::new(
+      &self.connection,
+      self.version,
+      session_path,
+      self.record_properties.clone(),
+    )
+    .await?;
+
+    Ok(Box::new(session))
+  }
+}
+
+/// Whether `path` names a session object of Mutter's ScreenCast service.
+fn is_session_path(path: &str) -> bool {
+  path
+    .strip_prefix(MUTTER_SESSION_PATH_PREFIX)
+    .is_some_and(|id| !id.is_empty())
+}
+
+struct MutterSession {
+  connection: Connection,
+  version: i32,
+  proxy: SessionProxy<'static>,
+  record_properties: Arc<HashMap<String, OwnedValue>>,
+  streams: Vec<CompositorStream>,
+  added_streams: Vec<PipeWireStreamAddedStream>,
+  closed_stream: Option<ClosedStream>,
+}
+
+impl MutterSession {
+  async fn new(
+    connection: &Connection,
+    version: i32,
+    object_path: OwnedObjectPath,
+    record_properties: Arc<HashMap<String, OwnedValue>>,
+  ) -> Result<Self, AnyError> {
+    let proxy = SessionProxy::new(connection, object_path).await?;
+    let closed_stream = proxy
+      .receive_closed()
+      .await
+      .context("failed to listen for the Mutter session closing")?;
+
+    Ok(Self {
+      connection: connection.clone(),
+      version,
+      proxy,
+      record_properties,
+      streams: Vec::new(),
+      added_streams: Vec::new(),
+      closed_stream: Some(closed_stream),
+    })
+  }
+
+  async fn new_stream(
+    &mut self,
+    id: u32,
+    source_type: SourceType,
+    object_path: OwnedObjectPath,
+    restore_data: StreamRestoreData,
+  ) -> Result<(), AnyError> {
+    let proxy = StreamProxy::new(&self.connection, object_path)
+      .await
+      .with_context(|| format!("failed to connect to Mutter stream {}", id))?;
+    let added_stream = proxy
+      .receive_pipe_wire_stream_added()
+      .await
+      .with_context(|| format!("failed to listen for the PipeWire node of stream {}", id))?;
+    let (position, size, mapping_id) = match proxy.parameters().await {
+      Ok(parameters) => {
+        let position = parameters
+          .get("position")
+          .and_then(|v| v.downcast_ref::<(i32, i32)>().ok());
+        let size = parameters.get("size").and_then(|v| v.downcast_ref::<(i32, i32)>().ok());
+        let mapping_id = parameters
+          .get("mapping-id")
+          .and_then(|v| v.downcast_ref::<&str>().ok())
+          .map(|s| s.to_string())
+          .or_else(|| {
+            parameters
+              .get("mapping-id")
+              .and_then(|v| v.downcast_ref::<String>().ok())
+          });
+        (position, size, mapping_id)
+      }
+      Err(e) => {
+        tracing::warn!("failed to fetch stream parameters: {}", e);
+        (None, None, None)
+      }
+    };
+
+    self.streams.push(CompositorStream {
+      id,
+      pipewire_node_id: None,
+      source_type,
+      position,
+      size,
+      mapping_id,
+      restore_data,
+    });
+    self.added_streams.push(added_stream);
+
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl CompositorSession for MutterSession {
+  async fn record_monitor(
+    &mut self,
+    id: u32,
+    connector: String,
+    match_string: String,
+    cursor_mode: CursorMode,
+  ) -> Result<(), AnyError> {
+    tracing::debug!(stream = id, %connector, "recording monitor");
+    let mut props = properties::for_record_call(&self.record_properties, false);
+    let cursor_mode_value = (cursor_mode as u32).into();
+    if self.version >= CURSOR_MODE_VERSION {
+      props.insert("cursor-mode", &cursor_mode_value);
+    }
+
+    let object_path = self
+      .proxy
+      .record_monitor(&connector, props)
+      .await
+      .with_context(|| format!("Mutter failed to record monitor {}", connector))?;
+    self
+      .new_stream(
+        id,
+        SourceType::Monitor,
+        object_path,
+        StreamRestoreData::Monitor {
+          match_string,
+          connector: connector.clone(),
+        },
+      )
+      .await
+  }
+
+  async fn record_window(
+    &mut self,
+    id: u32,
+    window_id: u64,
+    app_id: String,
+    title: String,
+    cursor_mode: CursorMode,
+  ) -> Result<(), AnyError> {
+    if self.version < RECORD_WINDOW_VERSION {
+      bail!("window recording needs ScreenCast version {}", RECORD_WINDOW_VERSION);
+    }
+    tracing::debug!(stream = id, window_id, %app_id, "recording window");
+
+    let mut props = properties::for_record_call(&self.record_properties, false);
+    let window_id_value = window_id.into();
+    let cursor_mode_value = (cursor_mode as u32).into();
+    props.insert("window-id", &window_id_value);
+    if self.version >= CURSOR_MODE_VERSION {
+      props.insert("cursor-mode", &cursor_mode_value);
+    }
+
+    let object_path = self
+      .proxy
+      .record_window(props)
+      .await
+      .with_context(|| format!("Mutter failed to record window {} of {}", window_id, app_id))?;
+    self
+      .new_stream(
+        id,
+        SourceType::Window,
+        object_path,
+        StreamRestoreData::Window { app_id, title },
+      )
+      .await
+  }
+
+  async fn record_virtual(&mut self, id: u32, cursor_mode: CursorMode) -> Result<(), AnyError> {
+    if self.version < RECORD_VIRTUAL_VERSION {
+      bail!("virtual monitors need ScreenCast version {}", RECORD_VIRTUAL_VERSION);
+    }
+    tracing::debug!(stream = id, "recording virtual monitor");
+
+    let mut props = properties::for_record_call(&self.record_properties, true);
+    let cursor_mode_value = (cursor_mode as u32).into();
+    if self.version >= CURSOR_MODE_VERSION {
+      props.insert("cursor-mode", &cursor_mode_value);
+    }
+
+    let object_path = self
+      .proxy
+      .record_virtual(props)
+      .await
+      .context("Mutter failed to create a virtual monitor")?;
+    self
+      .new_stream(id, SourceType::Virtual, object_path, StreamRestoreData::Virtual)
+      .await
+  }
+
+  async fn start(&mut self) -> Result<(), AnyError> {
+    tracing::debug!(streams = self.streams.len(), "starting Mutter session");
+    self
+      .proxy
+      .start()
+      .await
+      .context("Mutter failed to start the screencast session")?;
+
+    // mutter never announces a node for streams whose source went away in the meantime, those are skipped
+    let deadline = Instant::now() + PIPEWIRE_NODE_TIMEOUT;
+    for (stream, added_stream) in self.streams.iter_mut().zip(self.added_streams.iter_mut()) {
+      match select(added_stream.next(), Timer::at(deadline)).await {
+        Either::Left((Some(a), _)) => {
+          if let Ok(args) = a.args() {
+            stream.pipewire_node_id = Some(args.node_id);
+          }
+        }
+        Either::Left((None, _)) => {}
+        Either::Right(_) => {
+          tracing::warn!(stream = stream.id, "timed out waiting for PipeWire node");
+        }
+      }
+    }
+    self.streams.sort_by_key(|s| s.id);
+
+    Ok(())
+  }
+
+  async fn stop(&mut self) -> Result<(), AnyError> {
+    tracing::debug!("stopping Mutter session");
+    self
+      .proxy
+      .stop()
+      .await
+      .context("Mutter failed to stop the screencast session")?;
+
+    Ok(())
+  }
+
+  fn streams(&self) -> &[CompositorStream] {
+    &self.streams
+  }
+
+  fn take_closed(&mut self) -> Option<BoxFuture<'static, ()>> {
+    // mutter has no per-stream removal signal, it closes the whole session once any recorded source goes away
+    let mut closed_stream = self.closed_stream.take()?;
+
+    Some(
+      async move {
+        closed_stream.next().await;
+      }
+      .boxed(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn session_paths_are_validated() {
+    assert!(is_session_path("/org/gnome/Mutter/ScreenCast/Session/u1"));
+    assert!(is_session_path("/org/gnome/Mutter/ScreenCast/Session/u42"));
+    assert!(!is_session_path("/org/gnome/Mutter/ScreenCast/Session/"));
+    assert!(!is_session_path("/org/gnome/Mutter/ScreenCast/Stream/u1"));
+    assert!(!is_session_path("/org/example/ScreenCast/Session/u1"));
+    assert!(!is_session_path("/"));
+  }
+}
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ashpd::desktop::HandleToken;
+use async_channel::Sender;
+use async_lock::Mutex;
+use futures_util::StreamExt;
+use zbus::{Connection, Error as ZbusError, proxy, zvariant::Value};
+
+use crate::{
+  backend::{ScreencastSession, close_session},
+  common::ToUiMessage,
+};
+
+const STOP_ACTION: &str = "stop";
+
+#[proxy(
+  interface = "org.freedesktop.Notifications",
+  default_service = "org.freedesktop.Notifications",
+  default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+  #[allow(clippy::too_many_arguments)]
+  fn notify(
+    &self,
+    app_name: &str,
+    replaces_id: u32,
+    app_icon: &str,
+    summary: &str,
+    body: &str,
+    actions: &[&str],
+    hints: HashMap<&str, &Value<'_>>,
+    expire_timeout: i32,
+  ) -> zbus::Result<u32>;
+
+  #[zbus(signal)]
+  fn action_invoked(&self, id: u32, action_key: &str) -> zbus::Result<()>;
+
+  #[zbus(signal)]
+  fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Posts a desktop notification when a screencast starts, with an action that stops it again.
+pub(super) struct Notifier {
+  proxy: NotificationsProxy<'static>,
+  notifications: Arc<Mutex<HashMap<u32, HandleToken>>>,
+}
+
+impl Notifier {
+  pub(super) async fn new(
+    connection: &Connection,
+    sessions: Arc<Mutex<HashMap<HandleToken, ScreencastSession>>>,
+    ui_tx: Sender<ToUiMessage>,
+  ) -> Result<Self, ZbusError> {
+    let proxy = NotificationsProxy::new(connection).await?;
+    let notifications: Arc<Mutex<HashMap<u32, HandleToken>>> = Default::default();
+
+    let mut action_invoked = proxy.receive_action_invoked().await?;
+    let action_notifications = notifications.clone();
+    async_global_executor::spawn(async move {
+      while let Some(signal) = action_invoked.next().await {
+        let Ok(args) = signal.args() else {
+          continue;
+        };
+        if args.action_key != STOP_ACTION {
+          continue;
+        }
+        let Some(session_token) = action_notifications.lock().await.remove(&args.id) else {
+          continue;
+        };
+
+        tracing::info!("stopping session {} from notification", session_token);
+        if let Err(e) = close_session(&sessions, &ui_tx, &session_token).await {
+          tracing::warn!("failed to stop session {}: {:#}", session_token, e);
+        }
+      }
+    })
+    .detach();
+
+    let mut notification_closed = proxy.receive_notification_closed().await?;
+    let closed_notifications = notifications.clone();
+    async_global_executor::spawn(async move {
+      while let Some(signal) = notification_closed.next().await {
+        if let Ok(args) = signal.args() {
+          closed_notifications.lock().await.remove(&args.id);
+        }
+      }
+    })
+    .detach();
+
+    Ok(Self { proxy, notifications })
+  }
+
+  pub(super) async fn notify_started(&self, session_token: &HandleToken, app_id: Option<&str>) {
+    let body = match app_id {
+      Some(app_id) => format!("Screen sharing started with {}", app_id),
+      None => "Screen sharing started".to_string(),
+    };
+
+    match self
+      .proxy
+      .notify(
+        "Kagayaku",
+        0,
+        "",
+        "Screen sharing",
+        &body,
+        &[STOP_ACTION, "Stop sharing"],
+        HashMap::new(),
+        -1,
+      )
+      .await
+    {
+      Ok(id) => {
+        self.notifications.lock().await.insert(id, session_token.clone());
+      }
+      Err(e) => tracing::warn!("failed to post screencast notification: {}", e),
+    }
+  }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use zbus::zvariant::{OwnedValue, Value};
+
+/// Properties Mutter understands on `CreateSession`, with their D-Bus signature and the first
+/// ScreenCast API version that honors them.
+const SESSION_PROPERTIES: &[(&str, &str, i32)] =
+  &[("remote-desktop-session-id", "s", 1), ("disable-animations", "b", 3)];
+
+/// Properties Mutter understands on the `Record*` calls. `cursor-mode` and `window-id` are set by the backend itself.
+const RECORD_PROPERTIES: &[(&str, &str, i32)] = &[("is-recording", "b", 4), ("is-platform", "b", 3)];
+
+/// Record properties Mutter only takes on `RecordVirtual`.
+const VIRTUAL_RECORD_PROPERTIES: &[&str] = &["is-platform"];
+
+const RESERVED_RECORD_PROPERTIES: &[&str] = &["cursor-mode", "window-id"];
+
+/// Converts the configured `CreateSession` properties into D-Bus values.
+pub(super) fn session_properties(
+  config: &HashMap<String, toml::Value>,
+  version: Option<i32>,
+) -> HashMap<String, OwnedValue> {
+  convert("session", config, SESSION_PROPERTIES, &[], version)
+}
+
+/// Converts the configured `Record*` properties into D-Bus values.
+pub(super) fn record_properties(
+  config: &HashMap<String, toml::Value>,
+  version: Option<i32>,
+) -> HashMap<String, OwnedValue> {
+  convert("record", config, RECORD_PROPERTIES, RESERVED_RECORD_PROPERTIES, version)
+}
+
+/// Picks the converted record properties that apply to a `Record*` call, `RecordVirtual` when `virtual_monitor`.
+pub(super) fn for_record_call(
+  properties: &HashMap<String, OwnedValue>,
+  virtual_monitor: bool,
+) -> HashMap<&str, &Value<'static>> {
+  properties
+    .iter()
+    .filter(|(k, _)| virtual_monitor || !VIRTUAL_RECORD_PROPERTIES.contains(&k.as_str()))
+    .map(|(k, v)| (k.as_str(), &**v))
+    .collect()
+}
+
+fn convert(
+  kind: &str,
+  config: &HashMap<String, toml::Value>,
+  known: &[(&str, &str, i32)],
+  reserved: &[&str],
+  version: Option<i32>,
+) -> HashMap<String, OwnedValue> {
+  let mut properties = HashMap::new();
+
+  for (key, value) in config {
+    if reserved.contains(&key.as_str()) {
+      tracing::warn!("{} property {} is managed by kagayaku, ignoring", kind, key);
+      continue;
+    }
+
+    let converted = match known.iter().find(|(k, ..)| k == key) {
+      Some((_, signature, since)) => {
+        if version.is_some_and(|v| v < *since) {
+          tracing::warn!(
+            "{} property {} needs ScreenCast version {}, mutter may ignore it",
+            kind,
+            key,
+            since
+          );
+        }
+        let converted = to_typed_value(value, signature);
+        if converted.is_none() {
+          tracing::warn!("{} property {} must have D-Bus type {}, ignoring", kind, key, signature);
+        }
+        converted
+      }
+      None => {
+        tracing::warn!("unknown {} property {}, passing it to mutter as is", kind, key);
+        to_value(value)
+      }
+    };
+
+    if let Some(value) = converted.and_then(|v| v.try_into_owned().ok()) {
+      properties.insert(key.clone(), value);
+    }
+  }
+
+  properties
+}
+
+fn to_typed_value(value: &toml::Value, signature: &str) -> Option<Value<'static>> {
+  match (signature, value) {
+    ("b", toml::Value::Boolean(b)) => Some(Value::from(*b)),
+    ("s", toml::Value::String(s)) => Some(Value::from(s.clone())),
+    ("u", toml::Value::Integer(i)) => u32::try_from(*i).ok().map(Value::from),
+    _ => None,
+  }
+}
+
+fn to_value(value: &toml::Value) -> Option<Value<'static>> {
+  match value {
+    toml::Value::Boolean(b) => Some(Value::from(*b)),
+    toml::Value::String(s) => Some(Value::from(s.clone())),
+    toml::Value::Integer(i) => Some(Value::from(*i)),
+    toml::Value::Float(f) => Some(Value::from(*f)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(entries: &[(&str, toml::Value)]) -> HashMap<String, toml::Value> {
+    entries.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+  }
+
+  #[test]
+  fn known_properties_are_converted_to_their_type() {
+    let properties = record_properties(
+      &config(&[
+        ("is-recording", toml::Value::Boolean(true)),
+        ("is-platform", toml::Value::Boolean(false)),
+      ]),
+      Some(4),
+    );
+
+    assert_eq!(*properties["is-recording"], Value::from(true));
+    assert_eq!(*properties["is-platform"], Value::from(false));
+  }
+
+  #[test]
+  fn known_properties_of_wrong_type_are_dropped() {
+    let properties = record_properties(
+      &config(&[("is-recording", toml::Value::String("yes".to_string()))]),
+      Some(4),
+    );
+
+    assert!(properties.is_empty());
+  }
+
+  #[test]
+  fn unknown_properties_are_passed_as_is() {
+    let properties = session_properties(
+      &config(&[
+        ("future-flag", toml::Value::Boolean(true)),
+        ("future-count", toml::Value::Integer(3)),
+        ("future-table", toml::Value::Table(Default::default())),
+      ]),
+      Some(4),
+    );
+
+    assert_eq!(*properties["future-flag"], Value::from(true));
+    assert_eq!(*properties["future-count"], Value::from(3_i64));
+    assert!(!properties.contains_key("future-table"));
+  }
+
+  #[test]
+  fn reserved_properties_are_dropped() {
+    let properties = record_properties(
+      &config(&[
+        ("cursor-mode", toml::Value::Integer(1)),
+        ("window-id", toml::Value::Integer(42)),
+        ("is-recording", toml::Value::Boolean(true)),
+      ]),
+      Some(4),
+    );
+
+    assert_eq!(properties.keys().collect::<Vec<_>>(), ["is-recording"]);
+  }
+
+  #[test]
+  fn is_platform_is_only_sent_to_record_virtual() {
+    let properties = record_properties(
+      &config(&[
+        ("is-recording", toml::Value::Boolean(true)),
+        ("is-platform", toml::Value::Boolean(true)),
+      ]),
+      Some(3),
+    );
+
+    let mut monitor = for_record_call(&properties, false).into_keys().collect::<Vec<_>>();
+    let mut virtual_monitor = for_record_call(&properties, true).into_keys().collect::<Vec<_>>();
+    monitor.sort();
+    virtual_monitor.sort();
+    assert_eq!(monitor, ["is-recording"]);
+    assert_eq!(virtual_monitor, ["is-platform", "is-recording"]);
+  }
+}
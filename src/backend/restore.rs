@@ -0,0 +1,303 @@
+use std::mem::swap;
+
+use ashpd::desktop::screencast::SourceType;
+use regex::Regex;
+use zbus::zvariant::{Array, OwnedValue, Value};
+
+/// Strips the volatile parts of window titles (unread counters, unsaved markers, browser suffixes) so a window can be
+/// recognized again after its title churns. Normalized titles are also capped in length, so long titles don't bloat
+/// restore tokens; both stored and live titles go through here, so truncated titles still compare equal.
+pub struct TitleNormalizer {
+  patterns: Vec<Regex>,
+  max_length: usize,
+}
+
+impl TitleNormalizer {
+  pub fn new(patterns: &[String], max_length: usize) -> Self {
+    let patterns = patterns
+      .iter()
+      .filter_map(|p| match Regex::new(p) {
+        Ok(r) => Some(r),
+        Err(e) => {
+          tracing::warn!("ignoring invalid title pattern {:?}: {}", p, e);
+          None
+        }
+      })
+      .collect();
+
+    Self { patterns, max_length }
+  }
+
+  pub fn normalize(&self, title: &str) -> String {
+    let mut title = title.to_string();
+    for pattern in &self.patterns {
+      title = pattern.replace_all(&title, "").into_owned();
+    }
+
+    title
+      .trim()
+      .chars()
+      .take(self.max_length)
+      .collect::<String>()
+      .trim_end()
+      .to_string()
+  }
+}
+
+/// Levenshtein distance between two titles, divided by the length of the longer one so it lies in `0.0..=1.0`.
+pub fn title_distance(a: &str, b: &str) -> f64 {
+  let a = a.chars().collect::<Vec<_>>();
+  let b = b.chars().collect::<Vec<_>>();
+  let longest = a.len().max(b.len());
+  if longest == 0 {
+    return 0.0;
+  }
+
+  let mut previous = (0..=b.len()).collect::<Vec<_>>();
+  let mut current = vec![0; b.len() + 1];
+  for (i, ca) in a.iter().enumerate() {
+    current[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + usize::from(ca != cb);
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    }
+    swap(&mut previous, &mut current);
+  }
+
+  previous[b.len()] as f64 / longest as f64
+}
+
+/// How a stored stream was found among the current monitors and windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreMatch {
+  /// The monitor scored a perfect match, or a window of the app has the same normalized title.
+  Exact,
+  /// The monitor or window only matched within the configured thresholds.
+  Fuzzy,
+  NotFound,
+  /// The stream data is malformed or of an unknown source type.
+  Invalid,
+}
+
+impl RestoreMatch {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::Exact => "exact",
+      Self::Fuzzy => "fuzzy",
+      Self::NotFound => "not-found",
+      Self::Invalid => "invalid",
+    }
+  }
+}
+
+/// Restore data decoded from any format version still understood.
+pub struct RestoreData {
+  /// Seconds since the epoch when the choice was made, 0 if unknown.
+  pub created_at: i64,
+  /// Seconds since the epoch when the choice was last used, 0 if unknown.
+  pub last_used_at: i64,
+  /// `(stream id, source type, source data)` of every stored stream.
+  pub streams: Vec<(u32, u32, OwnedValue)>,
+}
+
+type Decoder = fn(&Value<'_>) -> Option<RestoreData>;
+
+/// Decoders for every restore data version still accepted. Older formats keep their decoder when the format advances,
+/// so tokens handed out before an upgrade still restore. Each of them returns the streams in the current shape.
+const DECODERS: &[(u32, Decoder)] = &[(1, decode_v1), (2, decode_v2)];
+
+impl RestoreData {
+  pub fn decode(version: u32, data: &Value<'_>) -> Option<Self> {
+    let Some((_, decode)) = DECODERS.iter().find(|(v, _)| *v == version) else {
+      tracing::debug!("no decoder for restore data version {}", version);
+      return None;
+    };
+
+    decode(data)
+  }
+}
+
+/// v1: like v2, but monitor streams only store the match string. They are upgraded to `(match_string, "")`, an empty
+/// connector standing for one that is not known.
+fn decode_v1(data: &Value<'_>) -> Option<RestoreData> {
+  let mut restore_data = decode_v2(data)?;
+  for (_, source_type, data) in &mut restore_data.streams {
+    if *source_type == SourceType::Monitor as u32
+      && let Ok(match_string) = data.downcast_ref::<&str>()
+      && let Ok(upgraded) = Value::from((match_string.to_string(), String::new())).try_into_owned()
+    {
+      *data = upgraded;
+    }
+  }
+
+  Some(restore_data)
+}
+
+/// v2: `(created_at, last_used_at, [(id, source_type, data)])`, where the data of monitor streams is
+/// `(match_string, connector)` and that of window streams `(app_id, title)`.
+fn decode_v2(data: &Value<'_>) -> Option<RestoreData> {
+  let (created_at, last_used_at, streams) = data.downcast_ref::<(i64, i64, Array)>().ok()?;
+  let streams = streams
+    .iter()
+    .filter_map(|s| s.to_owned().downcast::<(u32, u32, OwnedValue)>().ok())
+    .collect();
+
+  Some(RestoreData {
+    created_at,
+    last_used_at,
+    streams,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use zbus::zvariant::Signature;
+
+  use super::*;
+  use crate::config::RestoreConfig;
+
+  fn normalizer() -> TitleNormalizer {
+    let config = RestoreConfig::default();
+    TitleNormalizer::new(&config.title_patterns, config.max_title_length)
+  }
+
+  #[test]
+  fn default_patterns_strip_volatile_title_parts() {
+    let normalizer = normalizer();
+    let cases = [
+      (
+        "(3) Inbox - user@example.com - Gmail",
+        "Inbox - user@example.com - Gmail",
+      ),
+      ("(12) YouTube - Google Chrome", "YouTube"),
+      (
+        "Rust Programming Language — Mozilla Firefox",
+        "Rust Programming Language",
+      ),
+      ("(1) Slack | general – Chromium", "Slack | general"),
+      ("* Untitled Document 1 - gedit", "Untitled Document 1 - gedit"),
+      (
+        "● main.rs - kagayaku - Visual Studio Code",
+        "main.rs - kagayaku - Visual Studio Code",
+      ),
+      ("notes.txt •", "notes.txt"),
+      ("report.odt*", "report.odt"),
+      // only volatile parts at the ends are stripped
+      ("Inbox (3)", "Inbox (3)"),
+      ("Firefox - Mozilla Firefox Support", "Firefox - Mozilla Firefox Support"),
+      ("  Terminal  ", "Terminal"),
+      ("", ""),
+    ];
+
+    for (title, expected) in cases {
+      assert_eq!(normalizer.normalize(title), expected, "normalizing {:?}", title);
+    }
+  }
+
+  #[test]
+  fn churning_titles_normalize_to_the_same_title() {
+    let normalizer = normalizer();
+
+    assert_eq!(
+      normalizer.normalize("(2) Inbox — Mozilla Firefox"),
+      normalizer.normalize("Inbox — Mozilla Firefox")
+    );
+    assert_eq!(normalizer.normalize("● notes.txt"), normalizer.normalize("notes.txt"));
+  }
+
+  #[test]
+  fn invalid_patterns_are_ignored() {
+    let normalizer = TitleNormalizer::new(&["(".to_string(), r"\s+- Editor$".to_string()], 256);
+
+    assert_eq!(normalizer.normalize("notes.txt - Editor"), "notes.txt");
+  }
+
+  #[test]
+  fn long_titles_are_truncated_to_the_configured_length() {
+    let normalizer = normalizer();
+    let title = "a".repeat(100_000);
+
+    assert_eq!(normalizer.normalize(&title).chars().count(), 256);
+    // the live window keeps growing its title, the stored one still matches
+    assert_eq!(
+      normalizer.normalize(&title),
+      normalizer.normalize(&format!("{} and then some", title))
+    );
+  }
+
+  #[test]
+  fn truncation_counts_characters_not_bytes() {
+    let normalizer = TitleNormalizer::new(&[], 5);
+
+    assert_eq!(normalizer.normalize("日本語のタイトル"), "日本語のタ");
+    assert_eq!(normalizer.normalize("🎉🎉🎉🎉🎉🎉🎉"), "🎉🎉🎉🎉🎉");
+    assert_eq!(normalizer.normalize("e\u{301}e\u{301}e\u{301}"), "e\u{301}e\u{301}e");
+    // whitespace left at the cut is dropped, like around any other title
+    assert_eq!(normalizer.normalize("abcd efgh"), "abcd");
+  }
+
+  #[test]
+  fn truncated_titles_normalize_to_themselves() {
+    let normalizer = TitleNormalizer::new(&[r"\s+- Editor$".to_string()], 8);
+    let stored = normalizer.normalize("très long document - Editor");
+
+    assert_eq!(stored, "très lon");
+    assert_eq!(normalizer.normalize(&stored), stored);
+  }
+
+  #[test]
+  fn title_distance_is_relative_to_the_longer_title() {
+    assert_eq!(title_distance("", ""), 0.0);
+    assert_eq!(title_distance("notes.txt", "notes.txt"), 0.0);
+    assert_eq!(title_distance("abcdefghij", "abcdefgXYZ"), 0.3);
+    assert_eq!(title_distance("abcdefgXYZ", "abcdefghij"), 0.3);
+    assert_eq!(title_distance("abc", ""), 1.0);
+    assert_eq!(title_distance("日本語", "日本人"), 1.0 / 3.0);
+  }
+
+  fn v1_data() -> Value<'static> {
+    let mut streams = Array::new(&Signature::try_from("uuv").unwrap());
+    streams
+      .append(Value::from((
+        0_u32,
+        SourceType::Monitor as u32,
+        Value::from("DEL:DELL U2720Q:ABC123"),
+      )))
+      .unwrap();
+    streams
+      .append(Value::from((
+        1_u32,
+        SourceType::Window as u32,
+        Value::from(("org.gnome.TextEditor".to_string(), "notes.txt".to_string())),
+      )))
+      .unwrap();
+
+    Value::from((1_i64, 2_i64, streams))
+  }
+
+  #[test]
+  fn v1_monitor_streams_are_upgraded_to_v2_shape() {
+    let restore_data = RestoreData::decode(1, &v1_data()).unwrap();
+
+    assert_eq!((restore_data.created_at, restore_data.last_used_at), (1, 2));
+    let [(0, monitor_type, monitor), (1, window_type, window)] = restore_data.streams.as_slice() else {
+      panic!("expected both streams to decode");
+    };
+    assert_eq!(*monitor_type, SourceType::Monitor as u32);
+    assert_eq!(
+      monitor.downcast_ref::<(String, String)>().unwrap(),
+      ("DEL:DELL U2720Q:ABC123".to_string(), String::new())
+    );
+    assert_eq!(*window_type, SourceType::Window as u32);
+    assert_eq!(
+      window.downcast_ref::<(String, String)>().unwrap(),
+      ("org.gnome.TextEditor".to_string(), "notes.txt".to_string())
+    );
+  }
+
+  #[test]
+  fn unknown_versions_are_not_decoded() {
+    assert!(RestoreData::decode(0, &v1_data()).is_none());
+    assert!(RestoreData::decode(DECODERS.len() as u32 + 1, &v1_data()).is_none());
+  }
+}
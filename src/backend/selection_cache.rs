@@ -0,0 +1,222 @@
+use std::{collections::HashMap, env::var_os, fs, io::ErrorKind, path::PathBuf};
+
+use ashpd::desktop::screencast::SourceType;
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::{OwnedValue, Value};
+
+use crate::backend::restore::RestoreData;
+
+/// A stream of a cached selection, holding the same data as the restore data handed to the client.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum CachedStream {
+  Monitor {
+    id: u32,
+    match_string: String,
+    connector: String,
+  },
+  Window {
+    id: u32,
+    app_id: String,
+    title: String,
+  },
+  Virtual {
+    id: u32,
+  },
+}
+
+impl CachedStream {
+  /// `(stream id, source type, source data)` as stored in restore data.
+  pub fn restore_data(&self) -> (u32, u32, Value<'static>) {
+    match self {
+      Self::Monitor {
+        id,
+        match_string,
+        connector,
+      } => (
+        *id,
+        SourceType::Monitor as u32,
+        Value::from((match_string.to_string(), connector.to_string())),
+      ),
+      Self::Window { id, app_id, title } => (
+        *id,
+        SourceType::Window as u32,
+        Value::from((app_id.to_string(), title.to_string())),
+      ),
+      // a virtual monitor has nothing to match against, restoring it just creates a new one
+      Self::Virtual { id } => (*id, SourceType::Virtual as u32, Value::from("")),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CachedSelection {
+  created_at: i64,
+  last_used_at: i64,
+  streams: Vec<CachedStream>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CacheFile {
+  apps: HashMap<String, CachedSelection>,
+}
+
+/// Last remembered selection of every application, kept in `$XDG_STATE_HOME/kagayaku/selections.toml` for clients
+/// that ask for persistence but throw away the restore data they get back.
+pub struct SelectionCache {
+  path: PathBuf,
+  max_entries: usize,
+  file: CacheFile,
+}
+
+impl SelectionCache {
+  /// Reads the cache file, starting out empty if it is missing or invalid. `None` if there is no state directory.
+  pub fn load(max_entries: usize) -> Option<Self> {
+    let Some(path) = cache_path() else {
+      tracing::warn!("cannot determine state directory, not caching selections");
+      return None;
+    };
+
+    let file = match fs::read_to_string(&path) {
+      Ok(content) => toml::from_str(&content)
+        .inspect_err(|e| tracing::warn!("failed to parse selection cache {}: {}", path.display(), e))
+        .unwrap_or_default(),
+      Err(e) if e.kind() == ErrorKind::NotFound => CacheFile::default(),
+      Err(e) => {
+        tracing::warn!("failed to read selection cache {}: {}", path.display(), e);
+        CacheFile::default()
+      }
+    };
+
+    Some(Self {
+      path,
+      max_entries,
+      file,
+    })
+  }
+
+  /// The cached selection of `app_id`, in the shape of decoded restore data.
+  pub fn get(&self, app_id: &str) -> Option<RestoreData> {
+    let selection = self.file.apps.get(app_id)?;
+    let streams = selection
+      .streams
+      .iter()
+      .filter_map(|stream| {
+        let (id, source_type, data) = stream.restore_data();
+        Some((id, source_type, OwnedValue::try_from(data).ok()?))
+      })
+      .collect();
+
+    Some(RestoreData {
+      created_at: selection.created_at,
+      last_used_at: selection.last_used_at,
+      streams,
+    })
+  }
+
+  /// Remembers the selection of `app_id`, forgetting the least recently used applications once the cache is full.
+  pub fn store(&mut self, app_id: &str, created_at: i64, last_used_at: i64, streams: Vec<CachedStream>) {
+    self.file.apps.insert(
+      app_id.to_string(),
+      CachedSelection {
+        created_at,
+        last_used_at,
+        streams,
+      },
+    );
+    while self.file.apps.len() > self.max_entries {
+      let Some(oldest) = self
+        .file
+        .apps
+        .iter()
+        .min_by_key(|(_, s)| s.last_used_at)
+        .map(|(a, _)| a.to_string())
+      else {
+        break;
+      };
+      tracing::debug!(app_id = %oldest, "selection cache is full, forgetting the least recently used selection");
+      self.file.apps.remove(&oldest);
+    }
+
+    self.save();
+  }
+
+  fn save(&self) {
+    let content = match toml::to_string(&self.file) {
+      Ok(c) => c,
+      Err(e) => {
+        tracing::warn!("failed to serialize selection cache: {}", e);
+        return;
+      }
+    };
+    if let Some(dir) = self.path.parent()
+      && let Err(e) = fs::create_dir_all(dir)
+    {
+      tracing::warn!("failed to create {}: {}", dir.display(), e);
+      return;
+    }
+    if let Err(e) = fs::write(&self.path, content) {
+      tracing::warn!("failed to write selection cache {}: {}", self.path.display(), e);
+    }
+  }
+}
+
+fn cache_path() -> Option<PathBuf> {
+  let mut path = match var_os("XDG_STATE_HOME") {
+    Some(p) if !p.is_empty() => PathBuf::from(p),
+    _ => PathBuf::from(var_os("HOME")?).join(".local/state"),
+  };
+  path.push("kagayaku");
+  path.push("selections.toml");
+
+  Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env::temp_dir, process};
+
+  use super::*;
+
+  fn cache(name: &str, max_entries: usize) -> SelectionCache {
+    SelectionCache {
+      path: temp_dir()
+        .join(format!("kagayaku-test-{}-{}", process::id(), name))
+        .join("selections.toml"),
+      max_entries,
+      file: CacheFile::default(),
+    }
+  }
+
+  fn store(cache: &mut SelectionCache, app_id: &str, last_used_at: i64) {
+    cache.store(app_id, 1, last_used_at, vec![CachedStream::Virtual { id: 0 }]);
+  }
+
+  #[test]
+  fn least_recently_used_selection_is_evicted() {
+    let mut cache = cache("eviction", 2);
+
+    store(&mut cache, "org.example.A", 1);
+    store(&mut cache, "org.example.B", 2);
+    store(&mut cache, "org.example.C", 3);
+    assert!(cache.get("org.example.A").is_none());
+    assert!(cache.get("org.example.B").is_some());
+    assert!(cache.get("org.example.C").is_some());
+
+    // using B again makes C the oldest
+    store(&mut cache, "org.example.B", 4);
+    store(&mut cache, "org.example.D", 5);
+    assert!(cache.get("org.example.B").is_some());
+    assert!(cache.get("org.example.C").is_none());
+    assert!(cache.get("org.example.D").is_some());
+
+    let saved: CacheFile = toml::from_str(&fs::read_to_string(&cache.path).unwrap()).unwrap();
+    let mut apps = saved.apps.into_keys().collect::<Vec<_>>();
+    apps.sort();
+    assert_eq!(apps, ["org.example.B", "org.example.D"]);
+
+    fs::remove_dir_all(cache.path.parent().unwrap()).unwrap();
+  }
+}
@@ -0,0 +1,598 @@
+//! Stand-ins for the GNOME services and the compositor the backend talks to, so it can be driven without a session.
+//!
+//! The fake services are served over a peer-to-peer connection. Proxies on such a connection cannot resolve the
+//! owner of a well-known name, so they accept every signal of the right interface, which is all the tests need.
+
+use std::{
+  collections::HashMap,
+  os::unix::net::UnixStream,
+  sync::{Arc, Mutex as StdMutex, MutexGuard},
+};
+
+use anyhow::{Error as AnyError, bail};
+use ashpd::desktop::screencast::{CursorMode, SourceType};
+use async_channel::{Receiver, unbounded};
+use futures_util::{future::BoxFuture, try_join};
+use zbus::{
+  Connection, Guid, ObjectServer,
+  connection::Builder as ConnectionBuilder,
+  fdo, interface,
+  object_server::SignalEmitter,
+  zvariant::{OwnedObjectPath, OwnedValue, Value},
+};
+
+use crate::{
+  backend::{
+    ScreencastBackend,
+    capabilities::Capabilities,
+    compositor::{Compositor, CompositorSession, CompositorStream, SessionOptions, StreamRestoreData},
+    display_tracker::{ColorMode, Monitor},
+    mutter::MutterCompositor,
+    window_tracker::Window,
+  },
+  common::ToUiMessage,
+  config::Config,
+};
+
+const DISPLAY_CONFIG_PATH: &str = "/org/gnome/Mutter/DisplayConfig";
+const INTROSPECT_PATH: &str = "/org/gnome/Shell/Introspect";
+const SCREENCAST_PATH: &str = "/org/gnome/Mutter/ScreenCast";
+
+/// An external monitor with EDID data, at the origin of the layout.
+pub fn monitor(connector: &str, product: &str, serial: &str, size: Option<(i32, i32)>) -> Monitor {
+  Monitor {
+    connector: connector.to_string(),
+    vendor: "DEL".to_string(),
+    product: product.to_string(),
+    serial: serial.to_string(),
+    display_name: None,
+    builtin: false,
+    size,
+    refresh_rate: size.map(|_| 60.0),
+    x: 0,
+    y: 0,
+    scale: 1.0,
+    is_primary: false,
+    color_mode: ColorMode::Default,
+  }
+}
+
+pub fn window(app_id: &str, title: &str) -> Window {
+  Window {
+    app_id: app_id.to_string(),
+    title: title.to_string(),
+    is_hidden: false,
+  }
+}
+
+fn owned(value: impl Into<Value<'static>>) -> OwnedValue {
+  value.into().try_into_owned().expect("value holds no file descriptors")
+}
+
+/// What the fake GNOME services report and what was asked of them.
+#[derive(Default)]
+pub struct GnomeState {
+  pub monitors: Vec<Monitor>,
+  /// An empty app id or title is left out of `GetWindows`, like some XWayland windows do.
+  pub windows: Vec<(u64, Window)>,
+  pub focused_window: Option<u64>,
+  /// Makes `GetWindows` fail, like a shell that restricts introspection.
+  pub restrict_introspect: bool,
+  pub screencast: ScreenCastState,
+}
+
+#[derive(Default)]
+pub struct ScreenCastState {
+  /// Path handed out by `CreateSession` instead of a proper session path.
+  pub session_path: Option<String>,
+  pub sessions: u32,
+  pub streams: u32,
+  /// Connectors of the recorded monitors.
+  pub recorded_monitors: Vec<String>,
+  pub started: u32,
+  pub stopped: u32,
+}
+
+/// Which fake services to serve, a missing one fails every call like a service that is not running.
+pub struct Services {
+  pub display_config: bool,
+  pub introspect: bool,
+  pub screencast: bool,
+}
+
+impl Default for Services {
+  fn default() -> Self {
+    Self {
+      display_config: true,
+      introspect: true,
+      screencast: true,
+    }
+  }
+}
+
+pub type SharedGnomeState = Arc<StdMutex<GnomeState>>;
+
+fn lock(state: &SharedGnomeState) -> MutexGuard<'_, GnomeState> {
+  state.lock().expect("fake state is not poisoned")
+}
+
+struct FakeDisplayConfig(SharedGnomeState);
+
+type MonitorData = (
+  (String, String, String, String),
+  Vec<(String, i32, i32, f64, f64, Vec<f64>, HashMap<String, OwnedValue>)>,
+  HashMap<String, OwnedValue>,
+);
+type LogicalMonitorData = (
+  i32,
+  i32,
+  f64,
+  u32,
+  bool,
+  Vec<(String, String, String, String)>,
+  HashMap<String, OwnedValue>,
+);
+
+#[interface(name = "org.gnome.Mutter.DisplayConfig")]
+impl FakeDisplayConfig {
+  fn get_current_state(
+    &self,
+  ) -> (
+    u32,
+    Vec<MonitorData>,
+    Vec<LogicalMonitorData>,
+    HashMap<String, OwnedValue>,
+  ) {
+    let state = lock(&self.0);
+    let mut monitors = Vec::new();
+    let mut logical_monitors = Vec::new();
+
+    for m in &state.monitors {
+      let spec = (
+        m.connector.clone(),
+        m.vendor.clone(),
+        m.product.clone(),
+        m.serial.clone(),
+      );
+      let modes = m
+        .size
+        .map(|(w, h)| {
+          let properties = HashMap::from([("is-current".to_string(), owned(true))]);
+          (
+            format!("{}x{}@60", w, h),
+            w,
+            h,
+            m.refresh_rate.unwrap_or(60.0),
+            m.scale,
+            vec![1.0, m.scale],
+            properties,
+          )
+        })
+        .into_iter()
+        .collect();
+      let mut properties = HashMap::from([
+        ("is-builtin".to_string(), owned(m.builtin)),
+        ("color-mode".to_string(), owned(u32::from(m.is_hdr()))),
+      ]);
+      if let Some(display_name) = &m.display_name {
+        properties.insert("display-name".to_string(), owned(display_name.clone()));
+      }
+      // monitors without a current mode are not part of the layout
+      if m.is_active() {
+        logical_monitors.push((m.x, m.y, m.scale, 0, m.is_primary, vec![spec.clone()], HashMap::new()));
+      }
+      monitors.push((spec, modes, properties));
+    }
+
+    (1, monitors, logical_monitors, HashMap::new())
+  }
+
+  #[zbus(signal)]
+  async fn monitors_changed(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+struct FakeIntrospect(SharedGnomeState);
+
+#[interface(name = "org.gnome.Shell.Introspect")]
+impl FakeIntrospect {
+  fn get_windows(&self) -> fdo::Result<HashMap<u64, HashMap<String, OwnedValue>>> {
+    let state = lock(&self.0);
+    if state.restrict_introspect {
+      return Err(fdo::Error::AccessDenied("introspection is restricted".into()));
+    }
+
+    Ok(
+      state
+        .windows
+        .iter()
+        .map(|(id, w)| {
+          let mut properties = HashMap::from([
+            ("is-hidden".to_string(), owned(w.is_hidden)),
+            ("has-focus".to_string(), owned(state.focused_window == Some(*id))),
+          ]);
+          if !w.app_id.is_empty() {
+            properties.insert("app-id".to_string(), owned(w.app_id.clone()));
+          }
+          if !w.title.is_empty() {
+            properties.insert("title".to_string(), owned(w.title.clone()));
+          }
+          (*id, properties)
+        })
+        .collect(),
+    )
+  }
+
+  #[zbus(signal)]
+  async fn windows_changed(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+struct FakeScreenCast {
+  state: SharedGnomeState,
+  version: i32,
+}
+
+#[interface(name = "org.gnome.Mutter.ScreenCast")]
+impl FakeScreenCast {
+  async fn create_session(
+    &self,
+    _properties: HashMap<String, OwnedValue>,
+    #[zbus(object_server)] server: &ObjectServer,
+  ) -> fdo::Result<OwnedObjectPath> {
+    let path = {
+      let mut state = lock(&self.state);
+      state.screencast.sessions += 1;
+      match state.screencast.session_path.clone() {
+        Some(path) => path,
+        None => format!("{}/Session/u{}", SCREENCAST_PATH, state.screencast.sessions),
+      }
+    };
+    let path = OwnedObjectPath::try_from(path).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+    server
+      .at(
+        path.as_str(),
+        FakeScreenCastSession {
+          state: self.state.clone(),
+          streams: Vec::new(),
+        },
+      )
+      .await?;
+
+    Ok(path)
+  }
+
+  #[zbus(property)]
+  fn version(&self) -> i32 {
+    self.version
+  }
+}
+
+struct FakeScreenCastSession {
+  state: SharedGnomeState,
+  streams: Vec<OwnedObjectPath>,
+}
+
+impl FakeScreenCastSession {
+  async fn add_stream(&mut self, server: &ObjectServer) -> fdo::Result<OwnedObjectPath> {
+    let id = {
+      let mut state = lock(&self.state);
+      state.screencast.streams += 1;
+      state.screencast.streams
+    };
+    let path = OwnedObjectPath::try_from(format!("{}/Stream/u{}", SCREENCAST_PATH, id))
+      .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+    server.at(path.as_str(), FakeScreenCastStream).await?;
+    self.streams.push(path.clone());
+
+    Ok(path)
+  }
+}
+
+#[interface(name = "org.gnome.Mutter.ScreenCast.Session")]
+impl FakeScreenCastSession {
+  async fn record_monitor(
+    &mut self,
+    connector: &str,
+    _properties: HashMap<String, OwnedValue>,
+    #[zbus(object_server)] server: &ObjectServer,
+  ) -> fdo::Result<OwnedObjectPath> {
+    lock(&self.state)
+      .screencast
+      .recorded_monitors
+      .push(connector.to_string());
+    self.add_stream(server).await
+  }
+
+  async fn record_window(
+    &mut self,
+    _properties: HashMap<String, OwnedValue>,
+    #[zbus(object_server)] server: &ObjectServer,
+  ) -> fdo::Result<OwnedObjectPath> {
+    self.add_stream(server).await
+  }
+
+  async fn record_virtual(
+    &mut self,
+    _properties: HashMap<String, OwnedValue>,
+    #[zbus(object_server)] server: &ObjectServer,
+  ) -> fdo::Result<OwnedObjectPath> {
+    self.add_stream(server).await
+  }
+
+  /// Announces a PipeWire node for every stream, numbered from 40 in the order they were recorded.
+  async fn start(&self, #[zbus(connection)] connection: &Connection) -> fdo::Result<()> {
+    lock(&self.state).screencast.started += 1;
+    for (i, path) in self.streams.iter().enumerate() {
+      let emitter = SignalEmitter::new(connection, path.as_str())?;
+      FakeScreenCastStream::pipe_wire_stream_added(&emitter, 40 + i as u32).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn stop(&self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) -> fdo::Result<()> {
+    lock(&self.state).screencast.stopped += 1;
+    Self::closed(&emitter).await?;
+
+    Ok(())
+  }
+
+  #[zbus(signal)]
+  async fn closed(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+struct FakeScreenCastStream;
+
+#[interface(name = "org.gnome.Mutter.ScreenCast.Stream")]
+impl FakeScreenCastStream {
+  #[zbus(signal)]
+  async fn pipe_wire_stream_added(emitter: &SignalEmitter<'_>, node_id: u32) -> zbus::Result<()>;
+}
+
+/// A client connection to the fake services and the server connection serving them, which has to be kept around.
+pub async fn connect(state: &SharedGnomeState, services: Services) -> (Connection, Connection) {
+  let (client, server) = UnixStream::pair().expect("socket pair");
+  let mut server = ConnectionBuilder::unix_stream(server)
+    .server(Guid::generate())
+    .expect("valid guid")
+    .p2p();
+  if services.display_config {
+    server = server
+      .serve_at(DISPLAY_CONFIG_PATH, FakeDisplayConfig(state.clone()))
+      .expect("valid path");
+  }
+  if services.introspect {
+    server = server
+      .serve_at(INTROSPECT_PATH, FakeIntrospect(state.clone()))
+      .expect("valid path");
+  }
+  if services.screencast {
+    server = server
+      .serve_at(
+        SCREENCAST_PATH,
+        FakeScreenCast {
+          state: state.clone(),
+          version: 4,
+        },
+      )
+      .expect("valid path");
+  }
+
+  try_join!(ConnectionBuilder::unix_stream(client).p2p().build(), server.build()).expect("peer connections")
+}
+
+/// Emits `MonitorsChanged` from the fake DisplayConfig.
+pub async fn emit_monitors_changed(server: &Connection) {
+  let emitter = SignalEmitter::new(server, DISPLAY_CONFIG_PATH).expect("valid path");
+  FakeDisplayConfig::monitors_changed(&emitter)
+    .await
+    .expect("signal is sent");
+}
+
+/// What was asked of a [`MockCompositor`].
+#[derive(Default)]
+pub struct CompositorLog {
+  pub sessions: u32,
+  /// `monitor <connector>`, `window <id>` or `virtual`, in the order they were recorded.
+  pub recorded: Vec<String>,
+  pub started: u32,
+  pub stopped: u32,
+}
+
+/// A compositor that hands out PipeWire node `100 + stream id` for every stream, without any D-Bus in between.
+#[derive(Clone, Default)]
+pub struct MockCompositor {
+  pub log: Arc<StdMutex<CompositorLog>>,
+  /// Makes every record call fail.
+  pub fail_record: bool,
+  /// Starts sessions without handing out any node.
+  pub no_nodes: bool,
+}
+
+impl MockCompositor {
+  pub fn log(&self) -> MutexGuard<'_, CompositorLog> {
+    self.log.lock().expect("compositor log is not poisoned")
+  }
+}
+
+#[async_trait::async_trait]
+impl Compositor for MockCompositor {
+  async fn create_session(&self, _: &SessionOptions) -> Result<Box<dyn CompositorSession>, AnyError> {
+    self.log().sessions += 1;
+
+    Ok(Box::new(MockSession {
+      compositor: self.clone(),
+      streams: Vec::new(),
+    }))
+  }
+}
+
+struct MockSession {
+  compositor: MockCompositor,
+  streams: Vec<CompositorStream>,
+}
+
+impl MockSession {
+  fn record(
+    &mut self,
+    id: u32,
+    source_type: SourceType,
+    restore_data: StreamRestoreData,
+    entry: String,
+  ) -> Result<(), AnyError> {
+    if self.compositor.fail_record {
+      bail!("recording {} failed", entry);
+    }
+    self.compositor.log().recorded.push(entry);
+    self.streams.push(CompositorStream {
+      id,
+      pipewire_node_id: None,
+      source_type,
+      position: None,
+      size: None,
+      mapping_id: None,
+      restore_data,
+    });
+
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl CompositorSession for MockSession {
+  async fn record_monitor(
+    &mut self,
+    id: u32,
+    connector: String,
+    match_string: String,
+    _: CursorMode,
+  ) -> Result<(), AnyError> {
+    let entry = format!("monitor {}", connector);
+    self.record(
+      id,
+      SourceType::Monitor,
+      StreamRestoreData::Monitor {
+        match_string,
+        connector,
+      },
+      entry,
+    )
+  }
+
+  async fn record_window(
+    &mut self,
+    id: u32,
+    window_id: u64,
+    app_id: String,
+    title: String,
+    _: CursorMode,
+  ) -> Result<(), AnyError> {
+    self.record(
+      id,
+      SourceType::Window,
+      StreamRestoreData::Window { app_id, title },
+      format!("window {}", window_id),
+    )
+  }
+
+  async fn record_virtual(&mut self, id: u32, _: CursorMode) -> Result<(), AnyError> {
+    self.record(
+      id,
+      SourceType::Virtual,
+      StreamRestoreData::Virtual,
+      "virtual".to_string(),
+    )
+  }
+
+  async fn start(&mut self) -> Result<(), AnyError> {
+    self.compositor.log().started += 1;
+    if !self.compositor.no_nodes {
+      for stream in &mut self.streams {
+        stream.pipewire_node_id = Some(100 + stream.id);
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn stop(&mut self) -> Result<(), AnyError> {
+    self.compositor.log().stopped += 1;
+
+    Ok(())
+  }
+
+  fn streams(&self) -> &[CompositorStream] {
+    &self.streams
+  }
+
+  fn take_closed(&mut self) -> Option<BoxFuture<'static, ()>> {
+    None
+  }
+}
+
+/// A backend wired to the fake GNOME services.
+pub struct TestBackend {
+  pub backend: ScreencastBackend,
+  pub ui_rx: Receiver<ToUiMessage>,
+  pub gnome: SharedGnomeState,
+  /// Serves the fake services for as long as the backend is around.
+  pub server: Connection,
+}
+
+impl TestBackend {
+  /// Recording through `compositor`, with every service available and ScreenCast version 4.
+  pub async fn new(config: Config, gnome: GnomeState, compositor: Box<dyn Compositor>) -> Self {
+    Self::start(config, gnome, Services::default(), Some(compositor)).await
+  }
+
+  /// Recording through [`MutterCompositor`] talking to the fake ScreenCast service.
+  pub async fn mutter(config: Config, gnome: GnomeState) -> Self {
+    Self::start(config, gnome, Services::default(), None).await
+  }
+
+  pub async fn with_services(
+    config: Config,
+    gnome: GnomeState,
+    services: Services,
+    compositor: Box<dyn Compositor>,
+  ) -> Self {
+    Self::start(config, gnome, services, Some(compositor)).await
+  }
+
+  async fn start(
+    config: Config,
+    gnome: GnomeState,
+    services: Services,
+    compositor: Option<Box<dyn Compositor>>,
+  ) -> Self {
+    let gnome = Arc::new(StdMutex::new(gnome));
+    let (client, server) = connect(&gnome, services).await;
+    let (ui_tx, ui_rx) = unbounded();
+    let capabilities = Capabilities {
+      screencast_version: Some(4),
+      display_config: true,
+      introspect: true,
+    };
+    let compositor = match compositor {
+      Some(c) => c,
+      None => Box::new(
+        MutterCompositor::new(&client, &config.mutter, capabilities.screencast_version)
+          .await
+          .expect("fake ScreenCast is served"),
+      ),
+    };
+    let backend = ScreencastBackend::with_compositor(client, capabilities, compositor, ui_tx, config.into())
+      .await
+      .expect("backend starts");
+
+    Self {
+      backend,
+      ui_rx,
+      gnome,
+      server,
+    }
+  }
+
+  pub fn gnome(&self) -> MutexGuard<'_, GnomeState> {
+    lock(&self.gnome)
+  }
+}
@@ -1,50 +1,275 @@
-use std::collections::HashMap;
+use std::{
+  collections::HashMap,
+  time::{Duration, Instant},
+};
 
 use anyhow::{Context, Error as AnyError};
-use zbus::Connection;
+use zbus::{
+  Connection, Error as ZbusError,
+  zvariant::{OwnedValue, Value},
+};
 
-use crate::backend::generated::org_gnome_shell_introspect::IntrospectProxy;
+use crate::{
+  backend::{
+    drain_pending,
+    generated::org_gnome_shell_introspect::{IntrospectProxy, WindowsChangedStream},
+  },
+  common::APP_ID,
+};
 
 #[derive(Clone)]
 pub struct Window {
   pub app_id: String,
   pub title: String,
+  /// Minimized or otherwise not shown. Such windows stop producing frames while hidden.
+  pub is_hidden: bool,
 }
 
 pub struct WindowStateTracker {
   proxy: IntrospectProxy<'static>,
+  changed_stream: WindowsChangedStream,
+  last_refresh: Instant,
   windows: HashMap<u64, Window>,
+  /// Window ids grouped by app id, in ascending order.
+  windows_by_app: HashMap<String, Vec<u64>>,
+  /// Window ids by when they were last seen focused, most recent first. Only as precise as the refresh rate.
+  focus_history: Vec<u64>,
 }
 
 impl WindowStateTracker {
   pub async fn new(conn: &Connection) -> Result<Self, AnyError> {
-    let proxy = IntrospectProxy::new(conn).await?;
-    let mut tracker = Self {
-      proxy,
-      windows: HashMap::new(),
-    };
+    let mut tracker = Self::empty(conn).await?;
     tracker.refresh().await.context("failed to fetch window state")?;
 
     Ok(tracker)
   }
 
+  /// Creates a tracker without fetching the window state, for when Introspect is unavailable or restricted.
+  pub async fn empty(conn: &Connection) -> Result<Self, AnyError> {
+    let proxy = IntrospectProxy::new(conn).await?;
+    let changed_stream = proxy.receive_windows_changed().await?;
+
+    Ok(Self {
+      proxy,
+      changed_stream,
+      last_refresh: Instant::now(),
+      windows: HashMap::new(),
+      windows_by_app: HashMap::new(),
+      focus_history: Vec::new(),
+    })
+  }
+
   pub async fn refresh(&mut self) -> Result<(), AnyError> {
+    // whatever changed so far is picked up by this refresh
+    drain_pending(&mut self.changed_stream);
     let mut windows = HashMap::new();
     let proxy_resp = self.proxy.get_windows().await?;
 
     for (wid, window) in proxy_resp.iter() {
-      let app_id = window.get("app-id").unwrap().downcast_ref::<&str>().unwrap().into();
-      let title = window.get("title").unwrap().downcast_ref::<&str>().unwrap().into();
+      // the picker itself is never worth sharing, shell app ids come with a .desktop suffix
+      if get_str(window, "app-id").is_some_and(|a| a.trim_end_matches(".desktop") == APP_ID) {
+        continue;
+      }
+      // some XWayland windows come without an app id or title, keep them pickable instead of dropping them
+      let app_id = get_str(window, "app-id").unwrap_or_else(|| {
+        tracing::debug!("window {} has no app id", wid);
+        String::new()
+      });
+      let title = get_str(window, "title").unwrap_or_else(|| {
+        tracing::debug!("window {} has no title", wid);
+        format!("Window {}", wid)
+      });
+
+      let is_hidden = get_bool(window, "is-hidden").unwrap_or(false);
+      if get_bool(window, "has-focus").unwrap_or(false) {
+        self.focus_history.retain(|w| w != wid);
+        self.focus_history.insert(0, *wid);
+      }
 
-      windows.insert(*wid, Window { app_id, title });
+      windows.insert(
+        *wid,
+        Window {
+          app_id,
+          title,
+          is_hidden,
+        },
+      );
+    }
+
+    let mut windows_by_app: HashMap<_, Vec<_>> = HashMap::new();
+    for (wid, window) in windows.iter() {
+      windows_by_app.entry(window.app_id.clone()).or_default().push(*wid);
+    }
+    for ids in windows_by_app.values_mut() {
+      ids.sort_unstable();
     }
 
+    self.focus_history.retain(|w| windows.contains_key(w));
     self.windows = windows;
+    self.windows_by_app = windows_by_app;
+    self.last_refresh = Instant::now();
 
     Ok(())
   }
 
+  /// A separate stream of `WindowsChanged` signals, for waiting on changes without holding the tracker.
+  pub async fn subscribe_changes(&self) -> Result<WindowsChangedStream, ZbusError> {
+    self.proxy.receive_windows_changed().await
+  }
+
+  /// Whether `WindowsChanged` was emitted since the last call, without waiting for a signal to arrive.
+  pub fn has_changed(&mut self) -> bool {
+    drain_pending(&mut self.changed_stream)
+  }
+
+  pub fn is_stale(&self, max_age: Duration) -> bool {
+    self.last_refresh.elapsed() >= max_age
+  }
+
   pub fn windows(&self) -> &HashMap<u64, Window> {
     &self.windows
   }
+
+  /// Window ids with the most recently focused first, then the never focused ones from newest to oldest.
+  pub fn focus_order(&self) -> Vec<u64> {
+    let mut unfocused = self
+      .windows
+      .keys()
+      .filter(|w| !self.focus_history.contains(w))
+      .copied()
+      .collect::<Vec<_>>();
+    // window ids grow monotonically, so higher ids are newer windows
+    unfocused.sort_unstable_by(|a, b| b.cmp(a));
+
+    self.focus_history.iter().copied().chain(unfocused).collect()
+  }
+
+  pub fn windows_for_app<'a>(&'a self, app_id: &str) -> impl Iterator<Item = (u64, &'a Window)> + use<'a> {
+    self
+      .windows_by_app
+      .get(app_id)
+      .into_iter()
+      .flatten()
+      .filter_map(|wid| self.windows.get(wid).map(|window| (*wid, window)))
+  }
+}
+
+/// Reads a string property, accepting it whether it is sent as a plain string or wrapped in another variant.
+fn get_str(props: &HashMap<String, OwnedValue>, key: &str) -> Option<String> {
+  fn value_to_string(value: &Value<'_>) -> Option<String> {
+    match value {
+      Value::Str(s) => Some(s.to_string()),
+      Value::Value(v) => value_to_string(v),
+      _ => None,
+    }
+  }
+
+  props.get(key).and_then(|v| value_to_string(v))
+}
+
+fn get_bool(props: &HashMap<String, OwnedValue>, key: &str) -> Option<bool> {
+  fn value_to_bool(value: &Value<'_>) -> Option<bool> {
+    match value {
+      Value::Bool(b) => Some(*b),
+      Value::Value(v) => value_to_bool(v),
+      _ => None,
+    }
+  }
+
+  props.get(key).and_then(|v| value_to_bool(v))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex as StdMutex};
+
+  use async_global_executor::block_on;
+
+  use super::*;
+  use crate::backend::testing::{GnomeState, Services, connect, window};
+
+  fn props(value: Value<'static>) -> HashMap<String, OwnedValue> {
+    HashMap::from([("key".to_string(), value.try_into_owned().unwrap())])
+  }
+
+  #[test]
+  fn get_str_accepts_plain_and_nested_strings() {
+    assert_eq!(
+      get_str(&props(Value::from("Firefox")), "key").as_deref(),
+      Some("Firefox")
+    );
+    assert_eq!(
+      get_str(&props(Value::Value(Box::new(Value::from("Firefox")))), "key").as_deref(),
+      Some("Firefox")
+    );
+  }
+
+  #[test]
+  fn get_str_rejects_other_types_and_missing_keys() {
+    assert_eq!(get_str(&props(Value::from(3_u32)), "key"), None);
+    assert_eq!(get_str(&props(Value::from(true)), "key"), None);
+    assert_eq!(get_str(&props(Value::from("Firefox")), "other"), None);
+  }
+
+  #[test]
+  fn get_bool_accepts_plain_and_nested_booleans() {
+    assert_eq!(get_bool(&props(Value::from(true)), "key"), Some(true));
+    assert_eq!(
+      get_bool(&props(Value::Value(Box::new(Value::from(false)))), "key"),
+      Some(false)
+    );
+    assert_eq!(get_bool(&props(Value::from("true")), "key"), None);
+  }
+
+  #[test]
+  fn own_windows_are_not_offered() {
+    block_on(async {
+      let state = Arc::new(StdMutex::new(GnomeState {
+        windows: vec![
+          (1, window(APP_ID, "Share your screen")),
+          (2, window(&format!("{}.desktop", APP_ID), "Share your screen")),
+          (3, window(&format!("{}.Helper", APP_ID), "Helper")),
+          (4, window("org.gnome.TextEditor.desktop", "notes.txt")),
+        ],
+        ..Default::default()
+      }));
+      let (client, _server) = connect(&state, Services::default()).await;
+      let tracker = WindowStateTracker::new(&client).await.unwrap();
+
+      let mut ids = tracker.windows().keys().copied().collect::<Vec<_>>();
+      ids.sort_unstable();
+      assert_eq!(ids, [3, 4]);
+    });
+  }
+
+  #[test]
+  fn windows_without_app_id_or_title_are_kept() {
+    block_on(async {
+      let state = Arc::new(StdMutex::new(GnomeState {
+        windows: vec![
+          (1, window("", "xterm")),
+          (2, window("org.gnome.TextEditor", "")),
+          (3, window("", "")),
+        ],
+        ..Default::default()
+      }));
+      let (client, _server) = connect(&state, Services::default()).await;
+      let tracker = WindowStateTracker::new(&client).await.unwrap();
+
+      let mut windows = tracker
+        .windows()
+        .iter()
+        .map(|(id, w)| (*id, w.app_id.as_str(), w.title.as_str()))
+        .collect::<Vec<_>>();
+      windows.sort_unstable();
+      assert_eq!(
+        windows,
+        [
+          (1, "", "xterm"),
+          (2, "org.gnome.TextEditor", "Window 2"),
+          (3, "", "Window 3")
+        ]
+      );
+    });
+  }
 }
@@ -1,44 +1,84 @@
 use std::collections::HashMap;
 
 use ashpd::{
-  desktop::{PersistMode, screencast::SourceType},
+  desktop::{
+    PersistMode,
+    screencast::{CursorMode, SourceType},
+  },
   enumflags2::BitFlags,
 };
 use async_channel::Sender;
 
 use crate::backend::{display_tracker::Monitor, window_tracker::Window};
 
+/// Application id of the UI windows, also used to keep them out of the shareable windows.
+pub const APP_ID: &str = "com.hol.kagayaku";
+
+/// A source picked in the UI. `cursor_mode` overrides the cursor mode of the session for this stream only.
 pub enum ScreencastStreamChoice {
   Monitor {
     connector: String,
     match_string: String,
+    cursor_mode: Option<CursorMode>,
   },
   Window {
     window_id: u64,
     app_id: String,
     title: String,
+    cursor_mode: Option<CursorMode>,
   },
+  Virtual,
 }
 
 pub enum ToBackendMessage {
-  Success((bool, Vec<ScreencastStreamChoice>)),
+  Success {
+    remember: bool,
+    disable_animations: bool,
+    choices: Vec<ScreencastStreamChoice>,
+  },
   Cancel,
 }
 
 pub enum ToUiMessage {
-  NewPopup(PopupData),
+  NewPopup(Box<PopupData>),
+  SessionStarted(String, Option<String>),
   CloseSession(String),
 }
 
+pub enum ControlMessage {
+  StopSession(String),
+}
+
+/// Window of the requesting application that the popup should be attached to.
+#[derive(Clone)]
+pub enum ParentWindow {
+  /// Handle exported through xdg-foreign.
+  Wayland(String),
+  /// X11 window id.
+  X11(u64),
+}
+
 #[derive(Clone)]
 pub struct PopupData {
   pub session_token: String,
   pub app_id: Option<String>,
-  pub parent_window: Option<String>,
+  pub parent_window: Option<ParentWindow>,
   pub backend_tx: Sender<ToBackendMessage>,
   pub multiple: bool,
   pub source_type: BitFlags<SourceType>,
+  /// Cursor modes a stream can be switched to, only offered in the popup when there is more than one.
+  pub cursor_modes: BitFlags<CursorMode>,
+  /// Initial state of the "Disable animations" option, `None` when the compositor does not support it.
+  pub disable_animations: Option<bool>,
   pub persist_mode: PersistMode,
   pub monitors: HashMap<String, Monitor>,
   pub windows: HashMap<u64, Window>,
+  /// Ids of `windows` in the order they should be listed.
+  pub window_order: Vec<u64>,
+  /// Sources to select when the popup opens, from restore data that could not be used directly.
+  pub preselected_monitors: Vec<String>,
+  pub preselected_windows: Vec<u64>,
+  pub preselected_virtual: bool,
+  /// Number of restored sources that were found and that were expected, when restore data only partially matched.
+  pub partial_restore: Option<(usize, usize)>,
 }
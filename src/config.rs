@@ -0,0 +1,290 @@
+use std::{
+  collections::HashMap,
+  env::var_os,
+  fs,
+  io::ErrorKind,
+  path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// What to do when restore data resolves to more streams than the request allows.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestoreOverflow {
+  /// Keep the first restored stream and drop the rest.
+  #[default]
+  Truncate,
+  /// Ignore the restore data and show the picker instead.
+  Prompt,
+}
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RestoreConfig {
+  pub overflow: RestoreOverflow,
+  /// Regexes whose matches are removed from window titles before they are stored or compared.
+  pub title_patterns: Vec<String>,
+  /// Maximum number of characters of a window title kept in restore data.
+  pub max_title_length: usize,
+  /// Largest edit distance, relative to the title length, at which a window title still matches the stored one.
+  pub title_match_threshold: f64,
+  /// Lowest confidence at which a monitor still matches the stored one. A matching serial scores 0.4, the product
+  /// 0.35, plus 0.25 for the vendor when either of them matches.
+  pub monitor_match_threshold: f64,
+  /// Also remember the last selection of every application in `$XDG_STATE_HOME/kagayaku/selections.toml`, for
+  /// clients that ask for persistence but do not hand the restore data back.
+  pub cache_selections: bool,
+  /// Number of applications whose selection is cached, the least recently used ones are forgotten first.
+  pub cache_size: usize,
+}
+
+impl Default for RestoreConfig {
+  fn default() -> Self {
+    Self {
+      overflow: Default::default(),
+      title_patterns: [
+        // unread counters, e.g. "(3) Inbox"
+        r"^\(\d+\)\s*",
+        // unsaved markers, e.g. "* notes.txt" or "notes.txt •"
+        r"^[*●•]\s*",
+        r"\s*[*●•]$",
+        // browser suffixes
+        r"\s+[-—–]\s+(Mozilla Firefox|Google Chrome|Chromium)$",
+      ]
+      .into_iter()
+      .map(String::from)
+      .collect(),
+      max_title_length: 256,
+      title_match_threshold: 0.3,
+      monitor_match_threshold: 0.6,
+      cache_selections: false,
+      cache_size: 32,
+    }
+  }
+}
+
+/// Cursor mode used when the application does not ask for one.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DefaultCursorMode {
+  #[default]
+  Hidden,
+  Embedded,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SessionConfig {
+  pub cursor_mode: DefaultCursorMode,
+  /// Ask Mutter to turn off animations while sharing, needs ScreenCast version 3. Can be changed in the picker and
+  /// takes precedence over `disable-animations` in `mutter.session-properties`.
+  pub disable_animations: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+  Monitor,
+  Window,
+  Virtual,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ViewMode {
+  #[default]
+  Grid,
+  List,
+}
+
+/// Theme of the picker and the stop bar.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemePreference {
+  /// Follow the color scheme from the settings portal.
+  #[default]
+  System,
+  Light,
+  Dark,
+}
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct UiConfig {
+  pub source_order: Vec<SourceKind>,
+  pub view_mode: ViewMode,
+  /// Leave monitors without a current mode out of the picker instead of showing them disabled.
+  pub hide_inactive_monitors: bool,
+  /// Whether minimized windows are listed when the picker opens. They can always be shown from the picker.
+  pub show_hidden_windows: bool,
+  /// Post a desktop notification with a stop action whenever a screencast starts.
+  pub notify_on_start: bool,
+  /// Show an always-on-top "Stop sharing" bar while any screencast is running.
+  pub stop_overlay: bool,
+  /// Seconds to count down after pressing Share before sharing starts, giving a chance to abort. 0 shares right away.
+  pub share_countdown: u8,
+  /// Whether "Remember choice" starts checked.
+  pub remember_choice: bool,
+  /// Size of the picker window in logical pixels. Fitted to the number of choices when unset.
+  pub window_width: Option<f32>,
+  pub window_height: Option<f32>,
+  /// Ask the compositor to keep the picker and the stop bar above other windows.
+  pub always_on_top: bool,
+  pub theme: ThemePreference,
+}
+
+impl Default for UiConfig {
+  fn default() -> Self {
+    Self {
+      source_order: vec![SourceKind::Monitor, SourceKind::Window, SourceKind::Virtual],
+      view_mode: ViewMode::Grid,
+      hide_inactive_monitors: true,
+      show_hidden_windows: false,
+      notify_on_start: false,
+      stop_overlay: false,
+      share_countdown: 0,
+      remember_choice: true,
+      window_width: None,
+      window_height: None,
+      always_on_top: true,
+      theme: ThemePreference::System,
+    }
+  }
+}
+
+impl UiConfig {
+  /// The configured section order, with any source kind left out of it appended in the default order.
+  pub fn ordered_sources(&self) -> Vec<SourceKind> {
+    let mut order = Vec::new();
+    for kind in self
+      .source_order
+      .iter()
+      .chain(&[SourceKind::Monitor, SourceKind::Window, SourceKind::Virtual])
+    {
+      if !order.contains(kind) {
+        order.push(*kind);
+      }
+    }
+
+    order
+  }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TrackerConfig {
+  /// Seconds between safety-net refreshes of the monitor and window state. Disabled when unset or zero.
+  pub poll_interval: Option<u64>,
+  /// Allow dumping the tracked monitors and windows through the `DumpState` control method, and matching restore data
+  /// against them through `CheckRestoreData`. Always allowed in debug builds. Window titles can be sensitive, so this
+  /// is off otherwise.
+  pub allow_dump: bool,
+}
+
+/// Extra properties passed through to Mutter.
+///
+/// Mutter honors `disable-animations` (bool, ScreenCast version 3+) and `remote-desktop-session-id` (string) on
+/// sessions, `is-recording` (bool, version 4+) on recorded streams and `is-platform` (bool, version 3+) on virtual
+/// monitors only. Other keys are passed through as is.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MutterConfig {
+  pub session_properties: HashMap<String, toml::Value>,
+  pub record_properties: HashMap<String, toml::Value>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct StartupConfig {
+  /// How many more times to request the portal bus name when another instance holds it.
+  pub name_retries: u32,
+  /// Seconds to wait between bus name requests.
+  pub name_retry_delay: u64,
+}
+
+impl Default for StartupConfig {
+  fn default() -> Self {
+    Self {
+      name_retries: 0,
+      name_retry_delay: 1,
+    }
+  }
+}
+
+/// Shares a monitor without showing the picker, for kiosks and automated tests. Also enabled by setting
+/// `KAGAYAKU_AUTO_ACCEPT` to a non-empty value. Never use this on a desktop, any application can record the screen
+/// without asking while it is on.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AutoAcceptConfig {
+  pub enabled: bool,
+  /// Connector of the monitor to share, the primary monitor when unset.
+  pub connector: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+  pub auto_accept: AutoAcceptConfig,
+  pub mutter: MutterConfig,
+  pub restore: RestoreConfig,
+  pub session: SessionConfig,
+  pub startup: StartupConfig,
+  pub trackers: TrackerConfig,
+  pub ui: UiConfig,
+}
+
+impl Config {
+  /// Loads `$XDG_CONFIG_HOME/kagayaku/config.toml`, falling back to the defaults if it is missing or invalid.
+  pub fn load() -> Self {
+    let mut config = match config_path() {
+      Some(path) => Self::load_from(&path).unwrap_or_default(),
+      None => {
+        tracing::debug!("cannot determine config directory, using defaults");
+        Self::default()
+      }
+    };
+    if var_os("KAGAYAKU_AUTO_ACCEPT").is_some_and(|v| !v.is_empty()) {
+      config.auto_accept.enabled = true;
+    }
+
+    config
+  }
+
+  fn load_from(path: &Path) -> Option<Self> {
+    let content = match fs::read_to_string(path) {
+      Ok(c) => c,
+      Err(e) if e.kind() == ErrorKind::NotFound => {
+        tracing::debug!("no config file at {}", path.display());
+        return None;
+      }
+      Err(e) => {
+        tracing::warn!("failed to read config file {}: {}", path.display(), e);
+        return None;
+      }
+    };
+
+    match toml::from_str(&content) {
+      Ok(c) => {
+        tracing::info!("loaded config from {}", path.display());
+        Some(c)
+      }
+      Err(e) => {
+        tracing::warn!("failed to parse config file {}: {}", path.display(), e);
+        None
+      }
+    }
+  }
+}
+
+fn config_path() -> Option<PathBuf> {
+  let mut path = match var_os("XDG_CONFIG_HOME") {
+    Some(p) if !p.is_empty() => PathBuf::from(p),
+    _ => PathBuf::from(var_os("HOME")?).join(".config"),
+  };
+  path.push("kagayaku");
+  path.push("config.toml");
+
+  Some(path)
+}
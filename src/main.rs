@@ -1,9 +1,16 @@
 mod backend;
 mod common;
+mod config;
 mod ui;
 
+#[cfg(test)]
+#[path = "../build/modules.rs"]
+mod build_modules;
+
 use std::{
   io::stderr,
+  process::exit,
+  sync::Arc,
   thread::{Builder as ThreadBuilder, available_parallelism},
 };
 
@@ -12,7 +19,7 @@ use async_channel::unbounded;
 use async_global_executor::{GlobalExecutorConfig, block_on, init_with_config};
 use tracing_subscriber::{EnvFilter, Registry, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{backend::backend_main, ui::ui_main};
+use crate::{backend::backend_main, config::Config, ui::ui_main};
 
 fn main() -> Result<(), AnyError> {
   Registry::default()
@@ -22,18 +29,24 @@ fn main() -> Result<(), AnyError> {
     .with(fmt::layer().with_writer(stderr))
     .init();
 
+  let config = Arc::new(Config::load());
+
   init_with_config(GlobalExecutorConfig::default().with_max_threads(available_parallelism().map_or(1, |n| n.get())));
 
   let (tx, rx) = unbounded();
+  let (control_tx, control_rx) = unbounded();
+  let backend_config = config.clone();
 
   ThreadBuilder::new()
     .name("backend".into())
     .spawn(move || {
-      if let Err(e) = block_on(backend_main(tx)) {
-        tracing::error!("main function returns error: {}", e);
+      // the ui has nothing to show without the backend, so take the whole process down with it
+      if let Err(e) = block_on(backend_main(tx, control_rx, backend_config)) {
+        tracing::error!("main function returns error: {:#}", e);
+        exit(1);
       }
     })
     .context("failed to spawn backend thread")?;
 
-  ui_main(rx).context("ui event loop returns error")
+  ui_main(rx, control_tx, config).context("ui event loop returns error")
 }
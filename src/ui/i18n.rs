@@ -0,0 +1,51 @@
+use std::{env::var, sync::LazyLock};
+
+/// Translatable picker strings. `{}` in a string marks where the caller puts an argument.
+#[derive(Clone, Copy)]
+pub enum Phrase {
+  /// Prompt naming the requesting application.
+  PromptWithApp,
+  /// Prompt for requests without an app id.
+  Prompt,
+  Share,
+  Cancel,
+  RememberChoice,
+}
+
+impl Phrase {
+  fn english(self) -> &'static str {
+    match self {
+      Self::PromptWithApp => "Choose what to share with {}:",
+      Self::Prompt => "Choose what to share with the requesting application:",
+      Self::Share => "Share",
+      Self::Cancel => "Cancel",
+      Self::RememberChoice => "Remember this choice",
+    }
+  }
+}
+
+/// Language of the messages locale, e.g. `ja` for `ja_JP.UTF-8`.
+static LANGUAGE: LazyLock<String> = LazyLock::new(|| {
+  ["LC_ALL", "LC_MESSAGES", "LANG"]
+    .iter()
+    .filter_map(|v| var(v).ok())
+    .find(|l| !l.is_empty())
+    .and_then(|l| l.split(['_', '.', '@']).next().map(str::to_string))
+    .unwrap_or_default()
+});
+
+/// `phrase` in the language of the messages locale, English when there is no translation for it.
+pub fn tr(phrase: Phrase) -> &'static str {
+  translate(&LANGUAGE, phrase).unwrap_or_else(|| phrase.english())
+}
+
+fn translate(language: &str, phrase: Phrase) -> Option<&'static str> {
+  match (language, phrase) {
+    ("ja", Phrase::PromptWithApp) => Some("{} と共有するものを選択してください:"),
+    ("ja", Phrase::Prompt) => Some("要求元のアプリケーションと共有するものを選択してください:"),
+    ("ja", Phrase::Share) => Some("共有"),
+    ("ja", Phrase::Cancel) => Some("キャンセル"),
+    ("ja", Phrase::RememberChoice) => Some("この選択を記憶する"),
+    _ => None,
+  }
+}
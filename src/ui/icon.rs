@@ -0,0 +1,100 @@
+use std::{
+  env::{split_paths, var_os},
+  fs,
+  path::{Path, PathBuf},
+};
+
+use iced::widget::{image, svg};
+
+/// Icon used for windows whose application has no icon of its own.
+const FALLBACK_ICON: &str = "application-x-executable";
+/// Theme subdirectories searched for app icons, best fit for the choice cards first.
+const ICON_SIZES: &[&str] = &["48x48", "64x64", "scalable", "128x128", "256x256", "32x32"];
+
+#[derive(Clone)]
+pub enum Icon {
+  Raster(image::Handle),
+  Vector(svg::Handle),
+}
+
+impl Icon {
+  /// Resolves the icon of the application with `app_id` through its desktop entry, falling back to a generic
+  /// application icon.
+  pub fn lookup(app_id: &str) -> Option<Self> {
+    let data_dirs = data_dirs();
+    let name = desktop_entry_icon(&data_dirs, app_id).unwrap_or_else(|| app_id.to_string());
+
+    find_icon(&data_dirs, &name)
+      .or_else(|| find_icon(&data_dirs, FALLBACK_ICON))
+      .map(|path| {
+        if path.extension().is_some_and(|e| e == "svg") {
+          Self::Vector(svg::Handle::from_path(path))
+        } else {
+          Self::Raster(image::Handle::from_path(path))
+        }
+      })
+  }
+}
+
+/// `$XDG_DATA_HOME` followed by `$XDG_DATA_DIRS`, with the spec defaults for unset variables.
+fn data_dirs() -> Vec<PathBuf> {
+  let mut dirs = Vec::new();
+  if let Some(data_home) = var_os("XDG_DATA_HOME") {
+    dirs.push(PathBuf::from(data_home));
+  } else if let Some(home) = var_os("HOME") {
+    dirs.push(PathBuf::from(home).join(".local/share"));
+  }
+  match var_os("XDG_DATA_DIRS") {
+    Some(data_dirs) if !data_dirs.is_empty() => dirs.extend(split_paths(&data_dirs)),
+    _ => dirs.extend([PathBuf::from("/usr/local/share"), PathBuf::from("/usr/share")]),
+  }
+
+  dirs
+}
+
+/// Reads the `Icon` key of the `[Desktop Entry]` group of `<app_id>.desktop`.
+fn desktop_entry_icon(data_dirs: &[PathBuf], app_id: &str) -> Option<String> {
+  let file_name = format!("{}.desktop", app_id.trim_end_matches(".desktop"));
+  let content = data_dirs
+    .iter()
+    .find_map(|d| fs::read_to_string(d.join("applications").join(&file_name)).ok())?;
+
+  let mut in_entry = false;
+  for line in content.lines().map(str::trim) {
+    if line.starts_with('[') {
+      in_entry = line == "[Desktop Entry]";
+    } else if in_entry && let Some(icon) = line.strip_prefix("Icon=") {
+      return Some(icon.trim().to_string()).filter(|i| !i.is_empty());
+    }
+  }
+
+  None
+}
+
+/// Finds `name` in the hicolor theme or pixmaps. Absolute paths are used as they are.
+fn find_icon(data_dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+  if Path::new(name).is_absolute() {
+    return Path::new(name).is_file().then(|| PathBuf::from(name));
+  }
+
+  for dir in data_dirs {
+    for size in ICON_SIZES {
+      for extension in ["png", "svg"] {
+        let path = dir.join(format!("icons/hicolor/{}/apps/{}.{}", size, name, extension));
+        if path.is_file() {
+          return Some(path);
+        }
+      }
+    }
+  }
+  for dir in data_dirs {
+    for extension in ["png", "svg"] {
+      let path = dir.join(format!("pixmaps/{}.{}", name, extension));
+      if path.is_file() {
+        return Some(path);
+      }
+    }
+  }
+
+  None
+}
@@ -1,19 +1,37 @@
+mod i18n;
+mod icon;
 mod wayland;
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+  collections::{BTreeSet, HashMap, VecDeque},
+  fmt::{self, Display, Formatter},
+  iter::once,
+  pin::pin,
+  sync::Arc,
+  time::Duration,
+};
 
 use ashpd::{
-  desktop::{PersistMode, screencast::SourceType},
+  desktop::{
+    PersistMode,
+    screencast::{CursorMode, SourceType},
+    settings::{ColorScheme, Settings as PortalSettings},
+  },
   enumflags2::BitFlags,
 };
 use async_channel::{Receiver, Sender};
-use futures_util::SinkExt;
+use async_io::Timer;
+use futures_util::{SinkExt, Stream, StreamExt};
 use iced::{
-  Alignment, Element, Font, Length, Settings, Size, Subscription, Task, daemon, exit,
+  Alignment, Element, Event, Font, Length, Settings, Size, Subscription, Task, Theme, daemon, event, exit,
   font::Weight,
+  keyboard::{self, Key, key::Named},
   stream,
   wgpu::rwh::{RawDisplayHandle, RawWindowHandle},
-  widget::{self, button, checkbox, column, container, grid, rich_text, row, scrollable, space, span, text},
+  widget::{
+    self, button, checkbox, column, container, grid, pick_list, radio, rich_text, row, scrollable, space, span, text,
+    text_input, tooltip,
+  },
   window::{self, Level, close_requests, settings::PlatformSpecific},
 };
 use sctk::reexports::{
@@ -29,11 +47,32 @@ use tracing::instrument;
 
 use crate::{
   backend::{display_tracker::Monitor, window_tracker::Window},
-  common::{PopupData, ScreencastStreamChoice, ToBackendMessage, ToUiMessage},
-  ui::wayland::WaylandState,
+  common::{APP_ID, ControlMessage, ParentWindow, PopupData, ScreencastStreamChoice, ToBackendMessage, ToUiMessage},
+  config::{Config, SourceKind, ThemePreference, UiConfig, ViewMode},
+  ui::{
+    i18n::{Phrase, tr},
+    icon::Icon,
+    wayland::WaylandState,
+  },
 };
 
-const APP_ID: &str = "com.hol.kagayaku";
+/// Number of choices built per page. Every card is rebuilt and laid out on each frame, so with hundreds of windows the
+/// popup gets sluggish; beyond a page the user is better off narrowing the list than scrolling through it.
+///
+/// This is not a measured threshold, frame times depend too much on the GPU and on how many cards carry icons to pin
+/// one down. It is sized from the layout instead: the tallest popup leaves `POPUP_MAX_HEIGHT - POPUP_CHROME_HEIGHT`
+/// (480px) for choices, which is about 20 cards in the grid and 8 rows in the list, so a page is three screens of
+/// grid before "Show more" and the search take over.
+const CHOICE_PAGE_SIZE: usize = 60;
+
+const POPUP_WIDTH: f32 = 360.0;
+/// Height taken by everything around the choices: title, filters, search, buttons.
+const POPUP_CHROME_HEIGHT: f32 = 280.0;
+const POPUP_MIN_HEIGHT: f32 = 400.0;
+const POPUP_MAX_HEIGHT: f32 = 760.0;
+const GRID_COLUMNS: usize = 3;
+const GRID_ROW_HEIGHT: f32 = 72.0;
+const LIST_ROW_HEIGHT: f32 = 60.0;
 
 #[derive(Clone, Copy)]
 enum IncludeType {
@@ -42,10 +81,36 @@ enum IncludeType {
   Virtual,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 enum ChoiceType {
   Monitor(String),
   Window(u64),
+  Virtual,
+}
+
+/// Cursor mode picked for a single stream.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StreamCursor {
+  /// Whatever the application asked for.
+  Session,
+  Mode(CursorMode),
+}
+
+impl Display for StreamCursor {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Session => "App default",
+      Self::Mode(CursorMode::Hidden) => "Hide cursor",
+      Self::Mode(CursorMode::Embedded) => "Show cursor",
+      Self::Mode(CursorMode::Metadata) => "Cursor as metadata",
+    })
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Shortcut {
+  Share,
+  Cancel,
 }
 
 #[derive(Clone)]
@@ -53,11 +118,25 @@ enum Message {
   PopupReceived(Option<PopupData>),
   PopupCloseRequested(window::Id),
   PopupSessionClosed(String),
+  SessionStarted(String, Option<String>),
+  StopSharing,
   ToggleChoice(ChoiceType, bool),
+  ToggleAllMonitors(bool),
+  SetStreamCursor(ChoiceType, StreamCursor),
   ToggleInclude(IncludeType, bool),
   ToggleRemember(bool),
+  ToggleDisableAnimations(bool),
+  ToggleViewMode,
+  ToggleHiddenWindows(bool),
+  ShowMoreChoices,
+  SearchChanged(String),
+  SelectAll,
+  DeselectAll,
+  Shortcut(window::Id, Shortcut),
   Cancel,
   Share,
+  Tick,
+  ColorSchemeChanged(ColorScheme),
   WaylandReady(Connection, WlSurface, String),
   Exit,
   None,
@@ -67,9 +146,21 @@ struct State {
   include_monitor: bool,
   include_window: bool,
   include_virtual: bool,
-  selected_monitors: HashSet<String>,
-  selected_windows: HashSet<u64>,
+  show_hidden_windows: bool,
+  selected_monitors: BTreeSet<String>,
+  selected_windows: BTreeSet<u64>,
+  /// Whether a new virtual monitor is requested.
+  selected_virtual: bool,
+  /// Per-stream cursor modes, streams without an entry use the one of the session.
+  stream_cursors: HashMap<ChoiceType, CursorMode>,
   remember_choice: bool,
+  /// `None` when the compositor cannot disable animations.
+  disable_animations: Option<bool>,
+  view_mode: ViewMode,
+  choice_limit: usize,
+  search: String,
+  /// Seconds left until sharing starts, while counting down after Share was pressed.
+  counting_down: Option<u8>,
 }
 
 impl Default for State {
@@ -78,16 +169,36 @@ impl Default for State {
       include_monitor: true,
       include_window: true,
       include_virtual: true,
-      selected_monitors: HashSet::new(),
-      selected_windows: HashSet::new(),
+      show_hidden_windows: false,
+      selected_monitors: BTreeSet::new(),
+      selected_windows: BTreeSet::new(),
+      selected_virtual: false,
+      stream_cursors: HashMap::new(),
       remember_choice: true,
+      disable_animations: None,
+      view_mode: ViewMode::default(),
+      choice_limit: CHOICE_PAGE_SIZE,
+      search: String::new(),
+      counting_down: None,
     }
   }
 }
 
 impl State {
   fn selected_count(&self) -> usize {
-    self.selected_monitors.len() + self.selected_windows.len()
+    self.selected_monitors.len() + self.selected_windows.len() + usize::from(self.selected_virtual)
+  }
+
+  fn clear_selection(&mut self) {
+    self.selected_monitors.clear();
+    self.selected_windows.clear();
+    self.selected_virtual = false;
+  }
+
+  /// Whether any of `fields` contains the search text, ignoring case.
+  fn matches_search(&self, fields: &[&str]) -> bool {
+    let search = self.search.trim().to_lowercase();
+    search.is_empty() || fields.iter().any(|f| f.to_lowercase().contains(&search))
   }
 }
 
@@ -97,17 +208,183 @@ struct ActivePopup {
   backend_tx: Sender<ToBackendMessage>,
   multiple: bool,
   source_type: BitFlags<SourceType, u32>,
+  cursor_modes: BitFlags<CursorMode>,
   persist_mode: PersistMode,
   monitors: HashMap<String, Monitor>,
   windows: HashMap<u64, Window>,
+  window_order: Vec<u64>,
+  partial_restore: Option<(usize, usize)>,
   state: State,
   window_id: window::Id,
   parent_set: bool,
 }
 
+impl ActivePopup {
+  /// Monitors passing the include toggles and the search.
+  fn visible_monitors(&self, hide_inactive: bool) -> impl Iterator<Item = (&String, &Monitor)> {
+    let include = self.source_type.contains(SourceType::Monitor) && self.state.include_monitor;
+
+    self.monitors.iter().filter(move |(connector, monitor)| {
+      let display_name = monitor.display_name.as_deref().unwrap_or_default();
+      include
+        && (monitor.is_active() || !hide_inactive)
+        && self.state.matches_search(&[display_name, &monitor.product, connector])
+    })
+  }
+
+  /// Connectors of the monitors that can be shared, regardless of filters.
+  fn active_monitors(&self) -> impl Iterator<Item = &String> {
+    self
+      .monitors
+      .iter()
+      .filter(|(_, monitor)| monitor.is_active())
+      .map(|(connector, _)| connector)
+  }
+
+  /// Whether the "All displays" choice is offered, only when several monitors can be picked at once.
+  fn all_monitors_visible(&self) -> bool {
+    self.multiple
+      && self.source_type.contains(SourceType::Monitor)
+      && self.state.include_monitor
+      && self.active_monitors().nth(1).is_some()
+      && self.state.matches_search(&["All displays"])
+  }
+
+  /// Whether the virtual monitor choice passes the include toggles and the search.
+  fn virtual_visible(&self) -> bool {
+    self.source_type.contains(SourceType::Virtual)
+      && self.state.include_virtual
+      && self.state.matches_search(&["Virtual monitor"])
+  }
+
+  /// Windows passing the include toggles and the search.
+  fn visible_windows(&self) -> impl Iterator<Item = (&u64, &Window)> {
+    let include = self.source_type.contains(SourceType::Window) && self.state.include_window;
+
+    self
+      .window_order
+      .iter()
+      .filter_map(|window_id| self.windows.get(window_id).map(|window| (window_id, window)))
+      .filter(move |(_, window)| {
+        include
+          && (self.state.show_hidden_windows || !window.is_hidden)
+          && self.state.matches_search(&[&window.title, &window.app_id])
+      })
+  }
+
+  fn monitor_choice<'a>(&'a self, connector: &'a str, monitor: &'a Monitor) -> Element<'a, Message> {
+    let selected = self.state.selected_monitors.contains(connector);
+    let monitor_type = match (monitor.builtin, monitor.is_primary) {
+      (true, true) => "Primary built-in",
+      (true, false) => "Built-in",
+      (false, true) => "Primary external",
+      (false, false) => "External",
+    };
+    let hdr = if monitor.is_hdr() { ", HDR" } else { "" };
+    let scale = scale_text(monitor.scale);
+    let refresh_rate = match monitor.refresh_rate {
+      // 59.94 and friends keep a decimal so they can be told apart from 60
+      Some(r) if (r - r.round()).abs() >= 0.05 => format!(" @ {:.1}Hz", r),
+      Some(r) => format!(" @ {:.0}Hz", r),
+      None => String::new(),
+    };
+    // identical monitors share the same name, the connector is the only thing telling them apart
+    let body_text = if let Some((width, height)) = monitor.size {
+      text!(
+        "{} display on {} ({}x{}{}{}{})",
+        monitor_type,
+        connector,
+        width,
+        height,
+        refresh_rate,
+        scale,
+        hdr
+      )
+    } else {
+      text!("{} display on {} (off)", monitor_type, connector)
+    };
+
+    choice_button(
+      self.state.view_mode,
+      self.multiple,
+      selected,
+      None,
+      text!("{}", monitor.display_name.as_ref().unwrap_or(&monitor.product)),
+      body_text,
+      monitor
+        .is_active()
+        .then(|| Message::ToggleChoice(ChoiceType::Monitor(connector.to_string()), !selected)),
+    )
+  }
+
+  fn all_monitors_choice(&self) -> Element<'_, Message> {
+    let selected = self
+      .active_monitors()
+      .all(|connector| self.state.selected_monitors.contains(connector));
+
+    choice_button(
+      self.state.view_mode,
+      self.multiple,
+      selected,
+      None,
+      text("All displays"),
+      text!("Every one of the {} active displays", self.active_monitors().count()),
+      Some(Message::ToggleAllMonitors(!selected)),
+    )
+  }
+
+  fn virtual_choice(&self) -> Element<'_, Message> {
+    let selected = self.state.selected_virtual;
+
+    choice_button(
+      self.state.view_mode,
+      self.multiple,
+      selected,
+      None,
+      text("Virtual monitor"),
+      text("A new, empty monitor"),
+      Some(Message::ToggleChoice(ChoiceType::Virtual, !selected)),
+    )
+  }
+
+  fn window_choice<'a>(&'a self, window_id: u64, window: &'a Window, icon: Option<&Icon>) -> Element<'a, Message> {
+    let selected = self.state.selected_windows.contains(&window_id);
+
+    let choice = choice_button(
+      self.state.view_mode,
+      self.multiple,
+      selected,
+      icon,
+      text!("{}", window.title),
+      text!("{}", window.app_id),
+      Some(Message::ToggleChoice(ChoiceType::Window(window_id), !selected)),
+    );
+
+    // titles get cut off by the layout, browser tabs often only differ past that point
+    tooltip(
+      choice,
+      container(column![text(&window.title), text(&window.app_id).size(12)])
+        .padding(4)
+        .max_width(360)
+        .style(container::rounded_box),
+      tooltip::Position::FollowCursor,
+    )
+    .into()
+  }
+}
+
 struct Daemon {
+  config: Arc<Config>,
+  control_tx: Sender<ControlMessage>,
   active_popup: Option<ActivePopup>,
   queued_popups: VecDeque<PopupData>,
+  active_sessions: Vec<(String, Option<String>)>,
+  overlay: Option<window::Id>,
+  overlay_hidden: bool,
+  /// Resolved app icons by app id, `None` when neither the app's icon nor the fallback could be found.
+  icons: HashMap<String, Option<Icon>>,
+  /// Color scheme reported by the settings portal, `None` until it answers.
+  color_scheme: Option<ColorScheme>,
 }
 
 impl Daemon {
@@ -119,41 +396,86 @@ impl Daemon {
       backend_tx,
       multiple,
       source_type,
+      cursor_modes,
+      disable_animations,
       persist_mode,
       monitors,
       windows,
+      window_order,
+      preselected_monitors,
+      preselected_windows,
+      preselected_virtual,
+      partial_restore,
     } = popup_data;
 
     tracing::info!("starting ui popup for {}", session_token);
 
+    for window in windows.values() {
+      if !self.icons.contains_key(&window.app_id) {
+        self.icons.insert(window.app_id.clone(), Icon::lookup(&window.app_id));
+      }
+    }
+
+    let choice_count = [
+      (SourceType::Monitor, monitors.len()),
+      (SourceType::Window, windows.len()),
+      (SourceType::Virtual, 1),
+    ]
+    .into_iter()
+    .filter(|(t, _)| source_type.contains(*t))
+    .map(|(_, n)| n)
+    .sum();
     let (window_id, open_task) = window::open(window::Settings {
       platform_specific: PlatformSpecific {
         application_id: APP_ID.into(),
         ..Default::default()
       },
-      size: Size::new(360.0, 660.0),
+      size: popup_size(&self.config.ui, choice_count),
       position: window::Position::Centered,
-      level: Level::AlwaysOnTop,
+      level: window_level(&self.config.ui),
       exit_on_close_request: false,
       ..Default::default()
     });
 
+    // restored sources that are gone by now are just left out
+    let selected_monitors = preselected_monitors
+      .into_iter()
+      .filter(|c| monitors.get(c).is_some_and(Monitor::is_active))
+      .collect();
+    let selected_windows = preselected_windows
+      .into_iter()
+      .filter(|w| windows.contains_key(w))
+      .collect();
+
     self.active_popup = Some(ActivePopup {
       session_token,
       app_id,
       backend_tx,
       multiple,
       source_type,
+      cursor_modes,
       persist_mode,
       monitors,
       windows,
-      state: Default::default(),
+      window_order,
+      partial_restore,
+      state: State {
+        selected_monitors,
+        selected_windows,
+        selected_virtual: preselected_virtual && source_type.contains(SourceType::Virtual),
+        show_hidden_windows: self.config.ui.show_hidden_windows,
+        view_mode: self.config.ui.view_mode,
+        remember_choice: self.config.ui.remember_choice,
+        disable_animations,
+        ..Default::default()
+      },
       window_id,
       parent_set: false,
     });
 
-    open_task.then(move |id| {
-      if let Some(parent) = parent_window.clone() {
+    open_task.then(move |id| match parent_window.clone() {
+      Some(ParentWindow::Wayland(parent)) => {
+        tracing::info!("associating popup with Wayland parent through xdg-foreign");
         window::run(id, |w| {
           let Ok(RawWindowHandle::Wayland(window_handle)) = w.window_handle().map(|h| h.as_raw()) else {
             return Message::None;
@@ -174,13 +496,68 @@ impl Daemon {
           };
           Message::WaylandReady(conn, surface, parent)
         })
-      } else {
+      }
+      Some(ParentWindow::X11(xid)) => {
+        // the UI only talks Wayland, so there is no X11 connection to set WM_TRANSIENT_FOR through
+        tracing::info!("cannot associate popup with X11 parent {:#x}, keeping it centered", xid);
+        Task::none()
+      }
+      None => {
         tracing::info!("no parent window to associate");
         Task::none()
       }
     })
   }
 
+  /// Sends the current selection of the active popup to the backend.
+  fn share(&mut self) -> Task<Message> {
+    let Some(active_popup) = self.active_popup.as_ref() else {
+      return Task::none();
+    };
+
+    let mut choices = Vec::new();
+
+    for connector in &active_popup.state.selected_monitors {
+      if let Some(monitor) = active_popup.monitors.get(connector) {
+        choices.push(ScreencastStreamChoice::Monitor {
+          connector: connector.to_string(),
+          match_string: monitor.match_string(),
+          cursor_mode: active_popup
+            .state
+            .stream_cursors
+            .get(&ChoiceType::Monitor(connector.clone()))
+            .copied(),
+        });
+      }
+    }
+
+    for window_id in &active_popup.state.selected_windows {
+      if let Some(window) = active_popup.windows.get(window_id) {
+        choices.push(ScreencastStreamChoice::Window {
+          window_id: *window_id,
+          app_id: window.app_id.to_string(),
+          title: window.title.to_string(),
+          cursor_mode: active_popup
+            .state
+            .stream_cursors
+            .get(&ChoiceType::Window(*window_id))
+            .copied(),
+        });
+      }
+    }
+
+    if active_popup.state.selected_virtual {
+      choices.push(ScreencastStreamChoice::Virtual);
+    }
+    tracing::info!("sharing screencast request");
+
+    self.close_active_with(ToBackendMessage::Success {
+      remember: active_popup.state.remember_choice,
+      disable_animations: active_popup.state.disable_animations.unwrap_or(false),
+      choices,
+    })
+  }
+
   fn close_active_with(&mut self, backend_message: ToBackendMessage) -> Task<Message> {
     let Some(active_popup) = self.active_popup.take() else {
       tracing::warn!("there's no popup to close");
@@ -200,6 +577,52 @@ impl Daemon {
     }
   }
 
+  fn sync_overlay(&mut self) -> Task<Message> {
+    match self.overlay {
+      Some(window_id) if self.active_sessions.is_empty() => {
+        self.overlay = None;
+        window::close(window_id)
+      }
+      None if self.config.ui.stop_overlay && !self.overlay_hidden && !self.active_sessions.is_empty() => {
+        let (window_id, open_task) = window::open(window::Settings {
+          platform_specific: PlatformSpecific {
+            application_id: APP_ID.into(),
+            ..Default::default()
+          },
+          size: Size::new(360.0, 48.0),
+          resizable: false,
+          level: window_level(&self.config.ui),
+          exit_on_close_request: false,
+          ..Default::default()
+        });
+        self.overlay = Some(window_id);
+        open_task.discard()
+      }
+      _ => Task::none(),
+    }
+  }
+
+  fn close_session_popup(&mut self, session_token: String) -> Task<Message> {
+    if let Some(active_popup) = self.active_popup.as_ref()
+      && active_popup.session_token == session_token
+    {
+      if active_popup.parent_set {
+        tracing::info!(
+          "popup for session {} is associated with a parent, ignoring",
+          session_token
+        );
+        return Task::none();
+      } else {
+        tracing::info!("popup cancelled by backend for session {}", session_token);
+        return self.close_active_with(ToBackendMessage::Cancel);
+      }
+    } else {
+      self.queued_popups.retain(|p| p.session_token != session_token);
+    }
+
+    Task::none()
+  }
+
   #[instrument(skip_all)]
   fn update(&mut self, msg: Message) -> Task<Message> {
     match msg {
@@ -216,6 +639,12 @@ impl Daemon {
         exit()
       }
       Message::PopupCloseRequested(window_id) => {
+        if self.overlay == Some(window_id) {
+          tracing::info!("stop sharing overlay dismissed");
+          self.overlay = None;
+          self.overlay_hidden = true;
+          return window::close(window_id);
+        }
         let Some(active_popup) = self.active_popup.as_ref() else {
           return Task::none();
         };
@@ -226,21 +655,24 @@ impl Daemon {
         self.close_active_with(ToBackendMessage::Cancel)
       }
       Message::PopupSessionClosed(session_token) => {
-        if let Some(active_popup) = self.active_popup.as_ref()
-          && active_popup.session_token == session_token
-        {
-          if active_popup.parent_set {
-            tracing::info!(
-              "popup for session {} is associated with a parent, ignoring",
-              session_token
-            );
-            return Task::none();
-          } else {
-            tracing::info!("popup cancelled by backend for session {}", session_token);
-            return self.close_active_with(ToBackendMessage::Cancel);
+        self.active_sessions.retain(|(t, _)| *t != session_token);
+        let overlay_task = self.sync_overlay();
+
+        Task::batch([overlay_task, self.close_session_popup(session_token)])
+      }
+      Message::SessionStarted(session_token, app_id) => {
+        self.active_sessions.push((session_token, app_id));
+        self.overlay_hidden = false;
+        self.sync_overlay()
+      }
+      Message::StopSharing => {
+        for (session_token, _) in &self.active_sessions {
+          if let Err(e) = self
+            .control_tx
+            .try_send(ControlMessage::StopSession(session_token.to_string()))
+          {
+            tracing::warn!("failed to send message to backend: {}", e);
           }
-        } else {
-          self.queued_popups.retain(|p| p.session_token != session_token);
         }
 
         Task::none()
@@ -254,8 +686,7 @@ impl Daemon {
           ChoiceType::Monitor(connector) => {
             if selected {
               if !active_popup.multiple {
-                active_popup.state.selected_monitors.clear();
-                active_popup.state.selected_windows.clear();
+                active_popup.state.clear_selection();
               }
               active_popup.state.selected_monitors.insert(connector);
             } else {
@@ -265,18 +696,34 @@ impl Daemon {
           ChoiceType::Window(window_id) => {
             if selected {
               if !active_popup.multiple {
-                active_popup.state.selected_monitors.clear();
-                active_popup.state.selected_windows.clear();
+                active_popup.state.clear_selection();
               }
               active_popup.state.selected_windows.insert(window_id);
             } else {
               active_popup.state.selected_windows.remove(&window_id);
             }
           }
+          ChoiceType::Virtual => {
+            if selected && !active_popup.multiple {
+              active_popup.state.clear_selection();
+            }
+            active_popup.state.selected_virtual = selected;
+          }
         }
 
         Task::none()
       }
+      Message::SetStreamCursor(choice_type, cursor) => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        match cursor {
+          StreamCursor::Session => active_popup.state.stream_cursors.remove(&choice_type),
+          StreamCursor::Mode(mode) => active_popup.state.stream_cursors.insert(choice_type, mode),
+        };
+        Task::none()
+      }
       Message::ToggleInclude(include_type, include) => {
         let Some(active_popup) = self.active_popup.as_mut() else {
           return Task::none();
@@ -290,6 +737,14 @@ impl Daemon {
 
         Task::none()
       }
+      Message::ToggleDisableAnimations(disable_animations) => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.disable_animations = Some(disable_animations);
+        Task::none()
+      }
       Message::ToggleRemember(remember_choice) => {
         let Some(active_popup) = self.active_popup.as_mut() else {
           return Task::none();
@@ -298,38 +753,144 @@ impl Daemon {
         active_popup.state.remember_choice = remember_choice;
         Task::none()
       }
+      Message::ToggleAllMonitors(selected) => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        let connectors = active_popup.active_monitors().cloned().collect::<Vec<_>>();
+        if selected {
+          active_popup.state.selected_monitors.extend(connectors);
+        } else {
+          for connector in &connectors {
+            active_popup.state.selected_monitors.remove(connector);
+          }
+        }
+        Task::none()
+      }
+      Message::SelectAll => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+        if !active_popup.multiple {
+          return Task::none();
+        }
+
+        // inactive monitors can't be picked even when they are shown
+        let monitors = active_popup
+          .visible_monitors(self.config.ui.hide_inactive_monitors)
+          .filter(|(_, monitor)| monitor.is_active())
+          .map(|(connector, _)| connector.clone())
+          .collect::<Vec<_>>();
+        let windows = active_popup
+          .visible_windows()
+          .map(|(window_id, _)| *window_id)
+          .collect::<Vec<_>>();
+        active_popup.state.selected_monitors.extend(monitors);
+        active_popup.state.selected_windows.extend(windows);
+        active_popup.state.selected_virtual |= active_popup.virtual_visible();
+        Task::none()
+      }
+      Message::DeselectAll => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.clear_selection();
+        Task::none()
+      }
+      Message::ToggleHiddenWindows(show) => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.show_hidden_windows = show;
+        Task::none()
+      }
+      Message::ShowMoreChoices => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.choice_limit += CHOICE_PAGE_SIZE;
+        Task::none()
+      }
+      Message::SearchChanged(search) => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.search = search;
+        active_popup.state.choice_limit = CHOICE_PAGE_SIZE;
+        Task::none()
+      }
+      Message::ToggleViewMode => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
+
+        active_popup.state.view_mode = match active_popup.state.view_mode {
+          ViewMode::Grid => ViewMode::List,
+          ViewMode::List => ViewMode::Grid,
+        };
+        Task::none()
+      }
+      Message::Shortcut(window_id, shortcut) => {
+        let Some(active_popup) = self.active_popup.as_ref() else {
+          return Task::none();
+        };
+        if active_popup.window_id != window_id {
+          return Task::none();
+        }
+
+        match shortcut {
+          Shortcut::Share if active_popup.state.selected_count() > 0 => self.update(Message::Share),
+          Shortcut::Share => Task::none(),
+          Shortcut::Cancel => self.update(Message::Cancel),
+        }
+      }
       Message::Cancel => {
+        if let Some(active_popup) = self.active_popup.as_mut()
+          && active_popup.state.counting_down.take().is_some()
+        {
+          tracing::info!("share countdown aborted");
+          return Task::none();
+        }
+
         tracing::info!("cancel button pressed, cancelling request");
         self.close_active_with(ToBackendMessage::Cancel)
       }
       Message::Share => {
-        let Some(active_popup) = self.active_popup.as_ref() else {
+        let countdown = self.config.ui.share_countdown;
+        let Some(active_popup) = self.active_popup.as_mut() else {
           return Task::none();
         };
+        if active_popup.state.counting_down.is_some() {
+          return Task::none();
+        }
+        if countdown > 0 {
+          active_popup.state.counting_down = Some(countdown);
+          return Task::none();
+        }
 
-        let mut choices = Vec::new();
+        self.share()
+      }
+      Message::Tick => {
+        let Some(active_popup) = self.active_popup.as_mut() else {
+          return Task::none();
+        };
 
-        for connector in &active_popup.state.selected_monitors {
-          if let Some(monitor) = active_popup.monitors.get(connector) {
-            choices.push(ScreencastStreamChoice::Monitor {
-              connector: connector.to_string(),
-              match_string: monitor.match_string(),
-            });
+        match active_popup.state.counting_down {
+          Some(remaining) if remaining > 1 => {
+            active_popup.state.counting_down = Some(remaining - 1);
+            Task::none()
           }
-        }
-
-        for window_id in &active_popup.state.selected_windows {
-          if let Some(window) = active_popup.windows.get(window_id) {
-            choices.push(ScreencastStreamChoice::Window {
-              window_id: *window_id,
-              app_id: window.app_id.to_string(),
-              title: window.title.to_string(),
-            });
+          Some(_) => {
+            active_popup.state.counting_down = None;
+            self.share()
           }
+          None => Task::none(),
         }
-        tracing::info!("sharing screencast request");
-
-        self.close_active_with(ToBackendMessage::Success((active_popup.state.remember_choice, choices)))
       }
       Message::WaylandReady(conn, surface, parent) => {
         let Ok((globals, event_queue)) = registry_queue_init::<WaylandState>(&conn) else {
@@ -350,6 +911,11 @@ impl Daemon {
 
         Task::none()
       }
+      Message::ColorSchemeChanged(color_scheme) => {
+        tracing::debug!("color scheme changed to {:?}", color_scheme);
+        self.color_scheme = Some(color_scheme);
+        Task::none()
+      }
       Message::Exit => {
         if let Some(active_popup) = self.active_popup.take() {
           tracing::info!("closing active popup before exiting");
@@ -364,96 +930,144 @@ impl Daemon {
     }
   }
 
-  fn view(&self, _: window::Id) -> Element<'_, Message> {
+  fn overlay_view(&self) -> Element<'_, Message> {
+    let app_ids = self
+      .active_sessions
+      .iter()
+      .map(|(_, app_id)| app_id.as_deref().unwrap_or("unknown application"))
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    row![
+      text!("Sharing with {} ({})", app_ids, self.active_sessions.len()).width(Length::Fill),
+      button("Stop sharing").on_press(Message::StopSharing)
+    ]
+    .align_y(Alignment::Center)
+    .spacing(4)
+    .padding(4)
+    .into()
+  }
+
+  fn view(&self, window_id: window::Id) -> Element<'_, Message> {
+    if self.overlay == Some(window_id) {
+      return self.overlay_view();
+    }
+
     let Some(active_popup) = self.active_popup.as_ref() else {
       return text("Waiting for screencast request...").into();
     };
 
     let prompt: Element<_> = if let Some(ref app_id) = active_popup.app_id {
+      // translations may put the app id anywhere in the sentence
+      let (before, after) = tr(Phrase::PromptWithApp)
+        .split_once("{}")
+        .unwrap_or((tr(Phrase::PromptWithApp), ""));
       rich_text![
-        "Choose what to share with ",
+        before,
         span::<(), _>(app_id).font(Font {
           weight: Weight::Bold,
           ..Default::default()
         }),
-        ":"
+        after
       ]
       .into()
     } else {
-      "Choose what to share with the requesting application:".into()
+      tr(Phrase::Prompt).into()
+    };
+    let prompt: Element<_> = match active_popup.partial_restore {
+      Some((found, expected)) => column![
+        prompt,
+        text!(
+          "Only {} of the {} previously shared sources could be found, pick the missing ones again.",
+          found,
+          expected
+        )
+      ]
+      .spacing(4)
+      .into(),
+      None => prompt,
     };
 
-    let mut choices: Vec<Element<'_, Message>> = Vec::new();
+    let source_order = self.config.ui.ordered_sources();
 
-    if active_popup.source_type.contains(SourceType::Monitor) && active_popup.state.include_monitor {
-      for (connector, monitor) in &active_popup.monitors {
-        let selected = active_popup.state.selected_monitors.contains(connector);
-        let monitor_type = if monitor.builtin { "Built-in" } else { "External" };
-        let body_text = if let Some((width, height)) = monitor.size {
-          text!("{} display ({}x{})", monitor_type, width, height)
-        } else {
-          text!("{} display (unknown size)", monitor_type)
-        };
-
-        choices.push(
-          button(
-            column![
-              container(checkbox(selected)).center(Length::Fill),
-              text!("{}", monitor.display_name.as_ref().unwrap_or(&monitor.product))
-                .font(Font {
-                  weight: Weight::Bold,
-                  ..Default::default()
-                })
-                .align_x(Alignment::Center)
-                .width(Length::Fill),
-              body_text.align_x(Alignment::Center).width(Length::Fill)
-            ]
-            .spacing(4),
-          )
-          .on_press(Message::ToggleChoice(
-            ChoiceType::Monitor(connector.to_string()),
-            !selected,
-          ))
-          .into(),
-        );
+    let mut sections = Vec::new();
+    let mut shown_choices = 0;
+    let mut hidden_choices = 0;
+    for source_kind in &source_order {
+      let mut choices = Vec::new();
+      let header = match source_kind {
+        SourceKind::Monitor => "Displays",
+        SourceKind::Window => "Windows",
+        SourceKind::Virtual => "Virtual",
+      };
+      match source_kind {
+        SourceKind::Monitor => {
+          if active_popup.all_monitors_visible() {
+            choices.push(active_popup.all_monitors_choice());
+          }
+          for (connector, monitor) in active_popup.visible_monitors(self.config.ui.hide_inactive_monitors) {
+            if shown_choices < active_popup.state.choice_limit {
+              shown_choices += 1;
+              choices.push(active_popup.monitor_choice(connector, monitor));
+            } else {
+              hidden_choices += 1;
+            }
+          }
+        }
+        SourceKind::Window => {
+          for (window_id, window) in active_popup.visible_windows() {
+            if shown_choices < active_popup.state.choice_limit {
+              shown_choices += 1;
+              let icon = self.icons.get(&window.app_id).and_then(Option::as_ref);
+              choices.push(active_popup.window_choice(*window_id, window, icon));
+            } else {
+              hidden_choices += 1;
+            }
+          }
+        }
+        SourceKind::Virtual => {
+          if active_popup.virtual_visible() {
+            if shown_choices < active_popup.state.choice_limit {
+              shown_choices += 1;
+              choices.push(active_popup.virtual_choice());
+            } else {
+              hidden_choices += 1;
+            }
+          }
+        }
       }
-    }
-
-    if active_popup.source_type.contains(SourceType::Window) && active_popup.state.include_window {
-      for (window_id, window) in &active_popup.windows {
-        let selected = active_popup.state.selected_windows.contains(window_id);
-        choices.push(
-          button(
-            column![
-              container(checkbox(selected)).center(Length::Fill),
-              text!("{}", window.title)
-                .font(Font {
-                  weight: Weight::Bold,
-                  ..Default::default()
-                })
-                .align_x(Alignment::Center)
-                .width(Length::Fill),
-              text!("{}", window.app_id)
-                .align_x(Alignment::Center)
-                .width(Length::Fill)
-            ]
-            .spacing(4),
-          )
-          .on_press(Message::ToggleChoice(ChoiceType::Window(*window_id), !selected))
-          .into(),
-        );
+      if !choices.is_empty() {
+        sections.push((header, choices));
       }
     }
 
     let mut filter_children = Vec::new();
     filter_children.push("Include: ".into());
 
-    for source_type in active_popup.source_type {
-      let (state, label, include_type) = match source_type {
-        SourceType::Monitor => (active_popup.state.include_monitor, "Monitor", IncludeType::Monitor),
-        SourceType::Window => (active_popup.state.include_window, "Window", IncludeType::Window),
-        SourceType::Virtual => (active_popup.state.include_virtual, "Virtual", IncludeType::Virtual),
+    for source_kind in source_order {
+      let (source_type, state, label, include_type) = match source_kind {
+        SourceKind::Monitor => (
+          SourceType::Monitor,
+          active_popup.state.include_monitor,
+          "Monitor",
+          IncludeType::Monitor,
+        ),
+        SourceKind::Window => (
+          SourceType::Window,
+          active_popup.state.include_window,
+          "Window",
+          IncludeType::Window,
+        ),
+        SourceKind::Virtual => (
+          SourceType::Virtual,
+          active_popup.state.include_virtual,
+          "Virtual",
+          IncludeType::Virtual,
+        ),
       };
+      if !active_popup.source_type.contains(source_type) {
+        continue;
+      }
 
       filter_children.push(
         checkbox(state)
@@ -463,40 +1077,186 @@ impl Daemon {
       );
     }
 
-    let share_button: Element<_> = if active_popup.state.selected_count() > 0 {
-      button("Share").on_press(Message::Share).into()
+    if active_popup.source_type.contains(SourceType::Window) && active_popup.windows.values().any(|w| w.is_hidden) {
+      filter_children.push(
+        checkbox(active_popup.state.show_hidden_windows)
+          .label("Hidden windows")
+          .on_toggle(Message::ToggleHiddenWindows)
+          .into(),
+      );
+    }
+
+    filter_children.push(space::horizontal().into());
+    filter_children.push(
+      button(match active_popup.state.view_mode {
+        ViewMode::Grid => "List view",
+        ViewMode::List => "Grid view",
+      })
+      .on_press(Message::ToggleViewMode)
+      .into(),
+    );
+
+    // selections stay in place while their section is filtered out, so list them here to keep them visible
+    let selected_names = active_popup
+      .state
+      .selected_monitors
+      .iter()
+      .filter_map(|connector| active_popup.monitors.get(connector))
+      .map(|monitor| monitor.display_name.as_ref().unwrap_or(&monitor.product).as_str())
+      .chain(
+        active_popup
+          .state
+          .selected_windows
+          .iter()
+          .filter_map(|window_id| active_popup.windows.get(window_id))
+          .map(|window| window.title.as_str()),
+      )
+      .chain(active_popup.state.selected_virtual.then_some("Virtual monitor"))
+      .collect::<Vec<_>>();
+    let selection_summary = if selected_names.is_empty() {
+      text("Nothing selected")
+    } else {
+      text!("Selected: {}", selected_names.join(", "))
+    };
+    let mut selection_row = row![selection_summary.width(Length::Fill)]
+      .align_y(Alignment::Center)
+      .spacing(4);
+    if active_popup.multiple {
+      selection_row = selection_row.push(button("Select all").on_press(Message::SelectAll));
+    }
+    selection_row = selection_row.push(
+      button("Deselect all").on_press_maybe((active_popup.state.selected_count() > 0).then_some(Message::DeselectAll)),
+    );
+
+    // only worth the space when the cursor can actually be switched between modes
+    let stream_cursors = if active_popup.cursor_modes.len() > 1 {
+      let options = once(StreamCursor::Session)
+        .chain(active_popup.cursor_modes.iter().map(StreamCursor::Mode))
+        .collect::<Vec<_>>();
+      let selected = active_popup
+        .state
+        .selected_monitors
+        .iter()
+        .filter_map(|connector| {
+          let monitor = active_popup.monitors.get(connector)?;
+          Some((
+            ChoiceType::Monitor(connector.clone()),
+            monitor.display_name.as_ref().unwrap_or(&monitor.product).as_str(),
+          ))
+        })
+        .chain(active_popup.state.selected_windows.iter().filter_map(|window_id| {
+          let window = active_popup.windows.get(window_id)?;
+          Some((ChoiceType::Window(*window_id), window.title.as_str()))
+        }));
+
+      column(selected.map(|(choice_type, name)| {
+        let current = active_popup
+          .state
+          .stream_cursors
+          .get(&choice_type)
+          .map_or(StreamCursor::Session, |m| StreamCursor::Mode(*m));
+
+        row![
+          text(name).width(Length::Fill),
+          pick_list(options.clone(), Some(current), move |c| {
+            Message::SetStreamCursor(choice_type.clone(), c)
+          })
+        ]
+        .align_y(Alignment::Center)
+        .spacing(4)
+        .into()
+      }))
+      .spacing(4)
     } else {
-      button("Share").into()
+      column![]
     };
 
-    let cancel_button: Element<_> = button("Cancel").on_press(Message::Cancel).into();
+    let share_button: Element<_> = if let Some(remaining) = active_popup.state.counting_down {
+      text!("Sharing starts in {}…", remaining).into()
+    } else if active_popup.state.selected_count() > 0 {
+      button(tr(Phrase::Share)).on_press(Message::Share).into()
+    } else {
+      button(tr(Phrase::Share)).into()
+    };
 
-    let bottom_row = if active_popup.persist_mode == PersistMode::DoNot {
+    let cancel_button: Element<_> = if active_popup.state.counting_down.is_some() {
+      button("Abort").on_press(Message::Cancel).into()
+    } else {
+      button(tr(Phrase::Cancel)).on_press(Message::Cancel).into()
+    };
+
+    let mut bottom_row = if active_popup.persist_mode == PersistMode::DoNot {
       row![]
     } else {
       row![
         checkbox(active_popup.state.remember_choice)
-          .label("Remember this choice")
+          .label(tr(Phrase::RememberChoice))
           .on_toggle(Message::ToggleRemember)
       ]
+    };
+    if let Some(disable_animations) = active_popup.state.disable_animations {
+      bottom_row = bottom_row.push(
+        checkbox(disable_animations)
+          .label("Disable animations")
+          .on_toggle(Message::ToggleDisableAnimations),
+      );
     }
-    .push(space::horizontal())
-    .push(share_button)
-    .push(cancel_button)
-    .width(Length::Fill)
-    .spacing(4);
+    let bottom_row = bottom_row
+      .push(space::horizontal())
+      .push(share_button)
+      .push(cancel_button)
+      .width(Length::Fill)
+      .spacing(4);
 
-    column![
-      prompt,
-      scrollable(
-        grid(choices)
+    let view_mode = active_popup.state.view_mode;
+    let choices: Element<_> = column(sections.into_iter().map(|(header, choices)| {
+      let choices: Element<_> = match view_mode {
+        ViewMode::Grid => grid(choices)
           .spacing(4)
           .columns(3)
-          .height(widget::grid::aspect_ratio(16, 9)),
-      )
-      .auto_scroll(true)
-      .height(Length::Fill)
-      .width(Length::Fill),
+          .height(widget::grid::aspect_ratio(16, 9))
+          .into(),
+        ViewMode::List => column(choices).spacing(4).into(),
+      };
+
+      column![
+        text(header).font(Font {
+          weight: Weight::Bold,
+          ..Default::default()
+        }),
+        choices
+      ]
+      .spacing(4)
+      .into()
+    }))
+    .spacing(8)
+    .into();
+    let choices = if hidden_choices > 0 {
+      column![
+        choices,
+        container(button(text!("Show {} more", hidden_choices)).on_press(Message::ShowMoreChoices))
+          .center_x(Length::Fill)
+      ]
+      .spacing(4)
+      .into()
+    } else {
+      choices
+    };
+
+    // submitting captures Enter, so it doesn't share while the user is still typing
+    let search_input = text_input("Search", &active_popup.state.search)
+      .on_input(Message::SearchChanged)
+      .on_submit(Message::None);
+
+    column![
+      prompt,
+      search_input,
+      scrollable(choices)
+        .auto_scroll(true)
+        .height(Length::Fill)
+        .width(Length::Fill),
+      selection_row,
+      stream_cursors,
       row(filter_children).spacing(4),
       bottom_row
     ]
@@ -506,30 +1266,218 @@ impl Daemon {
   }
 
   fn subscription(&self) -> Subscription<Message> {
-    if self.active_popup.is_some() {
-      close_requests().map(Message::PopupCloseRequested)
+    let shortcuts = if self.active_popup.is_some() {
+      event::listen_with(shortcut_listener)
     } else {
       Subscription::none()
+    };
+
+    let countdown = if self
+      .active_popup
+      .as_ref()
+      .is_some_and(|p| p.state.counting_down.is_some())
+    {
+      Subscription::run(countdown_ticks)
+    } else {
+      Subscription::none()
+    };
+
+    // kept running while no window is open, so a popup opens with the current scheme
+    let color_scheme = if self.config.ui.theme == ThemePreference::System {
+      Subscription::run(color_scheme_changes)
+    } else {
+      Subscription::none()
+    };
+
+    if self.active_popup.is_some() || self.overlay.is_some() {
+      Subscription::batch([
+        close_requests().map(Message::PopupCloseRequested),
+        shortcuts,
+        countdown,
+        color_scheme,
+      ])
+    } else {
+      color_scheme
+    }
+  }
+
+  /// `None` leaves the choice to iced, which is the case until the settings portal reports a color scheme.
+  fn theme(&self) -> Option<Theme> {
+    match self.config.ui.theme {
+      ThemePreference::Light => Some(Theme::Light),
+      ThemePreference::Dark => Some(Theme::Dark),
+      ThemePreference::System => self.color_scheme.map(|c| match c {
+        ColorScheme::PreferDark => Theme::Dark,
+        // GNOME reports its light default as no preference
+        ColorScheme::PreferLight | ColorScheme::NoPreference => Theme::Light,
+      }),
+    }
+  }
+}
+
+/// The configured picker size, or one that fits `choice_count` choices without scrolling, up to a limit.
+fn popup_size(config: &UiConfig, choice_count: usize) -> Size {
+  let rows_height = match config.view_mode {
+    ViewMode::Grid => choice_count.div_ceil(GRID_COLUMNS) as f32 * GRID_ROW_HEIGHT,
+    ViewMode::List => choice_count as f32 * LIST_ROW_HEIGHT,
+  };
+  let fitted_height = (POPUP_CHROME_HEIGHT + rows_height).clamp(POPUP_MIN_HEIGHT, POPUP_MAX_HEIGHT);
+
+  Size::new(
+    config.window_width.unwrap_or(POPUP_WIDTH),
+    config.window_height.unwrap_or(fitted_height),
+  )
+}
+
+/// The scale shown next to a monitor's mode, e.g. `, 125%`, nothing when it is not scaled.
+fn scale_text(scale: f64) -> String {
+  // mutter reports fractional scales like 1.7518248558044434 that only fit the mode exactly, round them for display
+  if scale == 1.0 {
+    String::new()
+  } else {
+    format!(", {:.0}%", scale * 100.0)
+  }
+}
+
+fn window_level(config: &UiConfig) -> Level {
+  if config.always_on_top {
+    Level::AlwaysOnTop
+  } else {
+    Level::Normal
+  }
+}
+
+/// Maps Enter and Escape to share and cancel. Key presses captured by a widget, such as typing into the search box,
+/// are left alone.
+fn shortcut_listener(event: Event, status: event::Status, window_id: window::Id) -> Option<Message> {
+  if status != event::Status::Ignored {
+    return None;
+  }
+
+  match event {
+    Event::Keyboard(keyboard::Event::KeyPressed {
+      key: Key::Named(key), ..
+    }) => match key {
+      Named::Enter => Some(Message::Shortcut(window_id, Shortcut::Share)),
+      Named::Escape => Some(Message::Shortcut(window_id, Shortcut::Cancel)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+fn countdown_ticks() -> impl Stream<Item = Message> {
+  Timer::interval(Duration::from_secs(1)).map(|_| Message::Tick)
+}
+
+/// The current color scheme from the settings portal, followed by every change to it.
+fn color_scheme_changes() -> impl Stream<Item = Message> {
+  stream::channel(1, async |mut out| {
+    let settings = match PortalSettings::new().await {
+      Ok(s) => s,
+      Err(e) => {
+        tracing::warn!("settings portal is unavailable, not following the color scheme: {}", e);
+        return;
+      }
+    };
+    match settings.color_scheme().await {
+      Ok(color_scheme) => {
+        let _ = out.send(Message::ColorSchemeChanged(color_scheme)).await;
+      }
+      Err(e) => tracing::warn!("failed to read the color scheme: {}", e),
+    }
+
+    let changes = match settings.receive_color_scheme_changed().await {
+      Ok(c) => c,
+      Err(e) => {
+        tracing::warn!("failed to listen for color scheme changes: {}", e);
+        return;
+      }
+    };
+    let mut changes = pin!(changes);
+    while let Some(color_scheme) = changes.next().await {
+      if out.send(Message::ColorSchemeChanged(color_scheme)).await.is_err() {
+        break;
+      }
+    }
+  })
+}
+
+fn choice_button<'a>(
+  view_mode: ViewMode,
+  multiple: bool,
+  selected: bool,
+  icon: Option<&Icon>,
+  title: widget::Text<'a>,
+  body: widget::Text<'a>,
+  on_press: Option<Message>,
+) -> Element<'a, Message> {
+  let title = title.font(Font {
+    weight: Weight::Bold,
+    ..Default::default()
+  });
+  // radio indicators tell the user that picking another choice replaces the current one
+  let indicator = || -> Element<'a, Message> {
+    if multiple {
+      checkbox(selected).into()
+    } else {
+      let on_click = on_press.clone().unwrap_or(Message::None);
+      radio("", (), selected.then_some(()), |_| on_click).into()
     }
+  };
+  let header = || {
+    let icon: Option<Element<'a, Message>> = icon.map(|icon| match icon {
+      Icon::Raster(handle) => widget::image(handle.clone()).width(24).height(24).into(),
+      Icon::Vector(handle) => widget::svg(handle.clone()).width(24).height(24).into(),
+    });
+
+    row(once(indicator()).chain(icon)).align_y(Alignment::Center).spacing(8)
+  };
+
+  match view_mode {
+    ViewMode::Grid => button(
+      column![
+        container(header()).center(Length::Fill),
+        title.align_x(Alignment::Center).width(Length::Fill),
+        body.align_x(Alignment::Center).width(Length::Fill)
+      ]
+      .spacing(4),
+    )
+    .on_press_maybe(on_press)
+    .into(),
+    ViewMode::List => button(header().push(column![title, body].width(Length::Fill)))
+      .on_press_maybe(on_press)
+      .width(Length::Fill)
+      .into(),
   }
 }
 
-pub fn ui_main(ui_rx: Receiver<ToUiMessage>) -> iced::Result {
+pub fn ui_main(ui_rx: Receiver<ToUiMessage>, control_tx: Sender<ControlMessage>, config: Arc<Config>) -> iced::Result {
   tracing::info!("starting UI loop");
   daemon(
     move || {
       let ui_rx_clone = ui_rx.clone();
       (
         Daemon {
+          config: config.clone(),
+          control_tx: control_tx.clone(),
           active_popup: None,
           queued_popups: VecDeque::new(),
+          active_sessions: Vec::new(),
+          overlay: None,
+          overlay_hidden: false,
+          icons: HashMap::new(),
+          color_scheme: None,
         },
         Task::stream(stream::channel(10, async move |mut out| {
           let mut stop = false;
           while !stop {
             match ui_rx_clone.recv().await {
               Ok(ToUiMessage::NewPopup(d)) => {
-                out.send(Message::PopupReceived(Some(d))).await.unwrap();
+                out.send(Message::PopupReceived(Some(*d))).await.unwrap();
+              }
+              Ok(ToUiMessage::SessionStarted(t, a)) => {
+                out.send(Message::SessionStarted(t, a)).await.unwrap();
               }
               Ok(ToUiMessage::CloseSession(t)) => {
                 out.send(Message::PopupSessionClosed(t)).await.unwrap();
@@ -551,8 +1499,10 @@ pub fn ui_main(ui_rx: Receiver<ToUiMessage>) -> iced::Result {
     id: Some(APP_ID.into()),
     ..Default::default()
   })
-  .title(|daemon: &Daemon, _| {
-    if let Some(active_popup) = daemon.active_popup.as_ref()
+  .title(|daemon: &Daemon, window_id: window::Id| {
+    if daemon.overlay == Some(window_id) {
+      "Kagayaku".into()
+    } else if let Some(active_popup) = daemon.active_popup.as_ref()
       && let Some(app_id) = active_popup.app_id.as_ref()
     {
       format!("Share with {}", app_id)
@@ -561,5 +1511,424 @@ pub fn ui_main(ui_rx: Receiver<ToUiMessage>) -> iced::Result {
     }
   })
   .subscription(Daemon::subscription)
+  .theme(|daemon: &Daemon, _| daemon.theme())
   .run()
 }
+
+#[cfg(test)]
+mod tests {
+  use async_channel::unbounded;
+
+  use super::*;
+  use crate::backend::display_tracker::ColorMode;
+
+  fn monitor(connector: &str, serial: &str, size: Option<(i32, i32)>) -> Monitor {
+    Monitor {
+      connector: connector.to_string(),
+      vendor: "DEL".to_string(),
+      product: "DELL U2720Q".to_string(),
+      serial: serial.to_string(),
+      display_name: Some("Dell Inc. 27\"".to_string()),
+      builtin: false,
+      size,
+      refresh_rate: size.map(|_| 60.0),
+      x: 0,
+      y: 0,
+      scale: 1.0,
+      is_primary: false,
+      color_mode: ColorMode::Default,
+    }
+  }
+
+  fn window(app_id: &str, title: &str) -> Window {
+    Window {
+      app_id: app_id.to_string(),
+      title: title.to_string(),
+      is_hidden: false,
+    }
+  }
+
+  fn daemon(config: Config) -> Daemon {
+    Daemon {
+      config: config.into(),
+      control_tx: unbounded().0,
+      active_popup: None,
+      queued_popups: VecDeque::new(),
+      active_sessions: Vec::new(),
+      overlay: None,
+      overlay_hidden: false,
+      icons: HashMap::new(),
+      color_scheme: None,
+    }
+  }
+
+  /// Opens a popup offering `monitors` and `windows`, returning the channel the popup answers the backend on.
+  fn open_popup(
+    daemon: &mut Daemon,
+    multiple: bool,
+    monitors: Vec<Monitor>,
+    windows: Vec<(u64, Window)>,
+  ) -> Receiver<ToBackendMessage> {
+    let (backend_tx, backend_rx) = unbounded();
+    let _ = daemon.update(Message::PopupReceived(Some(PopupData {
+      session_token: "session".to_string(),
+      app_id: Some("org.example.App".to_string()),
+      parent_window: None,
+      backend_tx,
+      multiple,
+      source_type: SourceType::Monitor | SourceType::Window | SourceType::Virtual,
+      cursor_modes: CursorMode::Hidden | CursorMode::Embedded,
+      disable_animations: None,
+      persist_mode: PersistMode::DoNot,
+      window_order: windows.iter().map(|(id, _)| *id).collect(),
+      monitors: monitors.into_iter().map(|m| (m.connector.clone(), m)).collect(),
+      windows: windows.into_iter().collect(),
+      preselected_monitors: Vec::new(),
+      preselected_windows: Vec::new(),
+      preselected_virtual: false,
+      partial_restore: None,
+    })));
+
+    backend_rx
+  }
+
+  fn state(daemon: &Daemon) -> &State {
+    &daemon.active_popup.as_ref().expect("popup is open").state
+  }
+
+  #[test]
+  fn single_selection_replaces_previous_choice() {
+    let mut daemon = daemon(Config::default());
+    let _backend_rx = open_popup(
+      &mut daemon,
+      false,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      vec![(1, window("org.gnome.TextEditor", "notes.txt"))],
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Window(1), true));
+    assert!(state(&daemon).selected_monitors.is_empty());
+    assert_eq!(state(&daemon).selected_windows, BTreeSet::from([1]));
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Virtual, true));
+    assert!(state(&daemon).selected_windows.is_empty());
+    assert!(state(&daemon).selected_virtual);
+    assert_eq!(state(&daemon).selected_count(), 1);
+  }
+
+  #[test]
+  fn multiple_selection_accumulates_and_toggles_off() {
+    let mut daemon = daemon(Config::default());
+    let _backend_rx = open_popup(
+      &mut daemon,
+      true,
+      vec![
+        monitor("DP-1", "1", Some((3840, 2160))),
+        monitor("DP-2", "2", Some((3840, 2160))),
+      ],
+      vec![(1, window("org.gnome.TextEditor", "notes.txt"))],
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-2".into()), true));
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Window(1), true));
+    assert_eq!(state(&daemon).selected_count(), 3);
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), false));
+    assert_eq!(state(&daemon).selected_monitors, BTreeSet::from(["DP-2".to_string()]));
+
+    let _ = daemon.update(Message::DeselectAll);
+    assert_eq!(state(&daemon).selected_count(), 0);
+  }
+
+  #[test]
+  fn include_toggles_hide_sources_from_select_all() {
+    let mut daemon = daemon(Config::default());
+    let _backend_rx = open_popup(
+      &mut daemon,
+      true,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      vec![(1, window("org.gnome.TextEditor", "notes.txt"))],
+    );
+
+    let _ = daemon.update(Message::ToggleInclude(IncludeType::Monitor, false));
+    let _ = daemon.update(Message::ToggleInclude(IncludeType::Virtual, false));
+    let _ = daemon.update(Message::SelectAll);
+
+    assert!(state(&daemon).selected_monitors.is_empty());
+    assert_eq!(state(&daemon).selected_windows, BTreeSet::from([1]));
+    assert!(!state(&daemon).selected_virtual);
+  }
+
+  #[test]
+  fn share_sends_selection_and_remember_choice() {
+    let mut daemon = daemon(Config::default());
+    let backend_rx = open_popup(
+      &mut daemon,
+      true,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      vec![(1, window("org.gnome.TextEditor", "notes.txt"))],
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Window(1), true));
+    let _ = daemon.update(Message::SetStreamCursor(
+      ChoiceType::Window(1),
+      StreamCursor::Mode(CursorMode::Embedded),
+    ));
+    let _ = daemon.update(Message::ToggleRemember(false));
+    let _ = daemon.update(Message::Share);
+
+    assert!(daemon.active_popup.is_none());
+    let Ok(ToBackendMessage::Success { remember, choices, .. }) = backend_rx.try_recv() else {
+      panic!("expected a successful share");
+    };
+    assert!(!remember);
+    assert!(matches!(
+      choices.as_slice(),
+      [ScreencastStreamChoice::Window {
+        window_id: 1,
+        app_id,
+        title,
+        cursor_mode: Some(CursorMode::Embedded),
+      }] if app_id == "org.gnome.TextEditor" && title == "notes.txt"
+    ));
+  }
+
+  #[test]
+  fn share_waits_for_countdown() {
+    let mut config = Config::default();
+    config.ui.share_countdown = 2;
+    let mut daemon = daemon(config);
+    let backend_rx = open_popup(
+      &mut daemon,
+      false,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      Vec::new(),
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::Share);
+    let _ = daemon.update(Message::Tick);
+    assert!(backend_rx.try_recv().is_err());
+    assert_eq!(state(&daemon).counting_down, Some(1));
+
+    let _ = daemon.update(Message::Tick);
+    assert!(matches!(backend_rx.try_recv(), Ok(ToBackendMessage::Success { .. })));
+  }
+
+  #[test]
+  fn cancel_aborts_countdown_before_closing() {
+    let mut config = Config::default();
+    config.ui.share_countdown = 3;
+    let mut daemon = daemon(config);
+    let backend_rx = open_popup(
+      &mut daemon,
+      false,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      Vec::new(),
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::Share);
+    let _ = daemon.update(Message::Cancel);
+    assert!(daemon.active_popup.is_some());
+    assert!(backend_rx.try_recv().is_err());
+
+    let _ = daemon.update(Message::Cancel);
+    assert!(daemon.active_popup.is_none());
+    assert!(matches!(backend_rx.try_recv(), Ok(ToBackendMessage::Cancel)));
+  }
+
+  #[test]
+  fn queued_popup_opens_after_active_one_closes() {
+    let mut daemon = daemon(Config::default());
+    let first_rx = open_popup(
+      &mut daemon,
+      false,
+      vec![monitor("DP-1", "1", Some((3840, 2160)))],
+      Vec::new(),
+    );
+    let second_rx = open_popup(
+      &mut daemon,
+      false,
+      vec![monitor("DP-2", "2", Some((3840, 2160)))],
+      Vec::new(),
+    );
+    assert_eq!(daemon.queued_popups.len(), 1);
+
+    let _ = daemon.update(Message::Cancel);
+    assert!(matches!(first_rx.try_recv(), Ok(ToBackendMessage::Cancel)));
+    assert!(daemon.queued_popups.is_empty());
+    assert!(daemon.active_popup.as_ref().unwrap().monitors.contains_key("DP-2"));
+    assert!(second_rx.try_recv().is_err());
+  }
+
+  #[test]
+  fn choice_page_is_several_screens_of_the_tallest_grid() {
+    let rows = ((POPUP_MAX_HEIGHT - POPUP_CHROME_HEIGHT) / GRID_ROW_HEIGHT) as usize;
+    assert!(CHOICE_PAGE_SIZE >= 3 * rows * GRID_COLUMNS);
+  }
+
+  #[test]
+  fn show_more_extends_by_a_page_until_search_changes() {
+    let mut daemon = daemon(Config::default());
+    let _backend_rx = open_popup(&mut daemon, true, Vec::new(), Vec::new());
+
+    let _ = daemon.update(Message::ShowMoreChoices);
+    assert_eq!(state(&daemon).choice_limit, 2 * CHOICE_PAGE_SIZE);
+
+    let _ = daemon.update(Message::SearchChanged("term".into()));
+    assert_eq!(state(&daemon).choice_limit, CHOICE_PAGE_SIZE);
+  }
+
+  fn mixed_monitors() -> Vec<Monitor> {
+    vec![
+      monitor("DP-1", "1", Some((3840, 2160))),
+      monitor("DP-2", "2", None),
+      monitor("HDMI-A-1", "3", Some((1920, 1080))),
+    ]
+  }
+
+  fn visible_connectors(daemon: &Daemon, hide_inactive: bool) -> BTreeSet<String> {
+    daemon
+      .active_popup
+      .as_ref()
+      .unwrap()
+      .visible_monitors(hide_inactive)
+      .map(|(connector, _)| connector.clone())
+      .collect()
+  }
+
+  #[test]
+  fn inactive_monitors_are_hidden_or_shown_but_never_selected() {
+    let mut daemon = daemon(Config::default());
+    let _backend_rx = open_popup(&mut daemon, true, mixed_monitors(), Vec::new());
+
+    assert_eq!(
+      visible_connectors(&daemon, true),
+      BTreeSet::from(["DP-1".to_string(), "HDMI-A-1".to_string()])
+    );
+    assert_eq!(visible_connectors(&daemon, false).len(), 3);
+
+    let _ = daemon.update(Message::SelectAll);
+    assert_eq!(
+      state(&daemon).selected_monitors,
+      BTreeSet::from(["DP-1".to_string(), "HDMI-A-1".to_string()])
+    );
+  }
+
+  #[test]
+  fn select_all_skips_inactive_monitors_when_they_are_shown() {
+    let mut config = Config::default();
+    config.ui.hide_inactive_monitors = false;
+    let mut daemon = daemon(config);
+    let _backend_rx = open_popup(&mut daemon, true, mixed_monitors(), Vec::new());
+
+    let _ = daemon.update(Message::SelectAll);
+    assert!(!state(&daemon).selected_monitors.contains("DP-2"));
+
+    let _ = daemon.update(Message::DeselectAll);
+    let _ = daemon.update(Message::ToggleAllMonitors(true));
+    assert_eq!(state(&daemon).selected_monitors.len(), 2);
+    assert!(!state(&daemon).selected_monitors.contains("DP-2"));
+  }
+
+  #[test]
+  fn preselected_inactive_monitor_is_dropped() {
+    let mut daemon = daemon(Config::default());
+    let (backend_tx, _backend_rx) = unbounded();
+    let _ = daemon.update(Message::PopupReceived(Some(PopupData {
+      session_token: "session".to_string(),
+      app_id: None,
+      parent_window: None,
+      backend_tx,
+      multiple: true,
+      source_type: SourceType::Monitor.into(),
+      cursor_modes: CursorMode::Hidden.into(),
+      disable_animations: None,
+      persist_mode: PersistMode::DoNot,
+      monitors: mixed_monitors().into_iter().map(|m| (m.connector.clone(), m)).collect(),
+      windows: HashMap::new(),
+      window_order: Vec::new(),
+      preselected_monitors: vec!["DP-1".to_string(), "DP-2".to_string()],
+      preselected_windows: Vec::new(),
+      preselected_virtual: false,
+      partial_restore: Some((2, 2)),
+    })));
+
+    assert_eq!(state(&daemon).selected_monitors, BTreeSet::from(["DP-1".to_string()]));
+  }
+
+  #[test]
+  fn share_sends_match_string_of_each_selected_monitor() {
+    let mut daemon = daemon(Config::default());
+    let monitors = vec![
+      monitor("DP-1", "1", Some((3840, 2160))),
+      monitor("DP-2", "2", Some((3840, 2160))),
+      monitor("HDMI-A-1", "3", Some((1920, 1080))),
+    ];
+    let expected = monitors
+      .iter()
+      .map(|m| (m.connector.clone(), m.match_string()))
+      .filter(|(connector, _)| connector != "DP-2")
+      .collect::<Vec<_>>();
+    let backend_rx = open_popup(&mut daemon, true, monitors, Vec::new());
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("HDMI-A-1".into()), true));
+    let _ = daemon.update(Message::Share);
+
+    let Ok(ToBackendMessage::Success { choices, .. }) = backend_rx.try_recv() else {
+      panic!("expected a successful share");
+    };
+    let shared = choices
+      .into_iter()
+      .map(|c| match c {
+        ScreencastStreamChoice::Monitor {
+          connector,
+          match_string,
+          ..
+        } => (connector, match_string),
+        _ => panic!("expected only monitors to be shared"),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(shared, expected);
+  }
+
+  #[test]
+  fn share_skips_selected_monitor_that_went_away() {
+    let mut daemon = daemon(Config::default());
+    let backend_rx = open_popup(
+      &mut daemon,
+      true,
+      vec![
+        monitor("DP-1", "1", Some((3840, 2160))),
+        monitor("DP-2", "2", Some((3840, 2160))),
+      ],
+      Vec::new(),
+    );
+
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-1".into()), true));
+    let _ = daemon.update(Message::ToggleChoice(ChoiceType::Monitor("DP-2".into()), true));
+    daemon.active_popup.as_mut().unwrap().monitors.remove("DP-2");
+    let _ = daemon.update(Message::Share);
+
+    let Ok(ToBackendMessage::Success { choices, .. }) = backend_rx.try_recv() else {
+      panic!("expected a successful share");
+    };
+    assert!(matches!(
+      choices.as_slice(),
+      [ScreencastStreamChoice::Monitor { connector, .. }] if connector == "DP-1"
+    ));
+  }
+
+  #[test]
+  fn fractional_scales_are_shown_as_percentages() {
+    assert_eq!(scale_text(1.0), "");
+    assert_eq!(scale_text(1.25), ", 125%");
+    assert_eq!(scale_text(1.5), ", 150%");
+    assert_eq!(scale_text(1.751_824_8), ", 175%");
+    assert_eq!(scale_text(2.0), ", 200%");
+  }
+}